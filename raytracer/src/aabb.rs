@@ -4,6 +4,7 @@ use crate::rtweekend::interval;
 use crate::rtweekend::interval::Interval;
 use crate::rtweekend::ray::Ray;
 use crate::rtweekend::vec3::Point3;
+use crate::stats;
 
 #[derive(Clone, Debug)]
 pub struct Aabb {
@@ -44,6 +45,7 @@ impl Aabb {
     }
 
     pub fn hit(&self, r: &Ray, ray_t: &Interval) -> bool {
+        stats::count(|s| s.aabb_tests += 1);
         let mut interval = Interval {
             min: ray_t.min,
             max: ray_t.max,
@@ -1,34 +1,296 @@
+use clap::ValueEnum;
 use indicatif::ProgressBar;
-use std::fs::File;
-use std::io::Write;
+use serde::{Deserialize, Serialize};
 
 //use std::sync::{Arc, Mutex};
+use crate::onb::pdf::Pdf;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::mpsc::channel;
+use std::sync::Arc;
 use std::thread;
-//use std::time::Instant;
-use crate::onb::pdf::Pdf;
+use std::time::Instant;
 //use crate::onb::Onb;
 
+use crate::checkpoint::Checkpoint;
+use crate::delta_light::DeltaLight;
+use crate::environment::Environment;
+use crate::hittable_list::hittable::HitRecord;
 use crate::hittable_list::material::ScatterRecord;
 use crate::hittable_list::HitObject;
+use crate::light_tree::LightTree;
+use crate::output::Framebuffer;
 //use crate::hittable_list::HittableList;
-use crate::rtweekend::color::write_color;
+use crate::rtweekend::color::luminance;
 use crate::rtweekend::degrees_to_radians;
+use crate::rtweekend::distribution::Distribution2D;
 use crate::rtweekend::interval::Interval;
 //use crate::rtweekend::random_double;
 use crate::rtweekend::random_double_01;
+use crate::rtweekend::random_int;
 use crate::rtweekend::ray::Ray;
+use crate::rtweekend::seed_sample;
 use crate::rtweekend::vec3::Color;
 use crate::rtweekend::vec3::Point3;
 use crate::rtweekend::vec3::Vec3;
 use crate::rtweekend::INF;
+use crate::stats::{self, RenderStats, TileTime};
+
+const TILE_SIZE: u32 = 32;
+
+// Pixel rectangle of the full frame that gets rendered and written out.
+#[derive(Clone, Copy, Debug)]
+pub struct Crop {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+// Keeps adding stratified passes of min_spp samples to a pixel until its estimated
+// error drops below threshold or samples_per_pixel is reached; the error is only
+// trusted from the second pass on. It is the standard error of the luminance mean
+// carried through a square root, which is close to the sRGB encoding of the output,
+// so the threshold is roughly in display units (0.01 is about 2.5 of 255 levels).
+#[derive(Clone, Copy, Debug)]
+pub struct Adaptive {
+    pub min_spp: u32,
+    pub threshold: f64,
+}
+impl Adaptive {
+    // Each pass is a square grid of strata, so min_spp has to be a square.
+    pub fn validate(&self) -> Result<(), String> {
+        let side = (self.min_spp as f64).sqrt().round() as u32;
+        if self.min_spp == 0 || side * side != self.min_spp {
+            return Err(format!(
+                "{} is not a square number of samples (1, 4, 9, 16, ...)",
+                self.min_spp
+            ));
+        }
+        Ok(())
+    }
+}
+
+// What the camera ray of one sample saw at its first hit. Misses keep the
+// background as albedo and zero for everything else.
+#[derive(Clone, Copy, Debug)]
+pub struct Aov {
+    pub albedo: Color,
+    pub normal: Vec3,
+    pub depth: f64, // Distance from the ray origin
+    pub position: Point3,
+    pub object_id: u32,
+}
+impl Aov {
+    pub fn new() -> Aov {
+        Aov {
+            albedo: Color::new(),
+            normal: Vec3::new(),
+            depth: 0.0,
+            position: Point3::new(),
+            object_id: 0,
+        }
+    }
+}
+
+// Colour, sample count and passes of each pixel of a tile, row by row.
+pub type TilePixels = Vec<(Color, u32, Aov)>;
+
+// Per-pixel passes; all but the object id are averaged over the pixel's samples,
+// the object id is the one its first sample hit.
+pub struct AovImages {
+    pub albedo: Framebuffer,
+    pub normal: Framebuffer,
+    pub depth: Framebuffer,
+    pub position: Framebuffer,
+    pub object_id: Framebuffer,
+}
+impl AovImages {
+    fn new(width: u32, height: u32) -> AovImages {
+        AovImages {
+            albedo: Framebuffer::new(width, height),
+            normal: Framebuffer::new(width, height),
+            depth: Framebuffer::new(width, height),
+            position: Framebuffer::new(width, height),
+            object_id: Framebuffer::new(width, height),
+        }
+    }
+    // Name of each pass with the channels worth keeping from it.
+    pub fn layers(&self) -> [(&'static str, &Framebuffer, &'static [&'static str]); 5] {
+        [
+            ("albedo", &self.albedo, &["R", "G", "B"]),
+            ("normal", &self.normal, &["X", "Y", "Z"]),
+            ("depth", &self.depth, &["Z"]),
+            ("position", &self.position, &["X", "Y", "Z"]),
+            ("object_id", &self.object_id, &["id"]),
+        ]
+    }
+    fn set(&mut self, x: u32, y: u32, aov: &Aov) {
+        let grey = |c: f64| Color { e: [c, c, c] };
+        self.albedo.set(x, y, aov.albedo);
+        self.normal.set(x, y, aov.normal);
+        self.depth.set(x, y, grey(aov.depth));
+        self.position.set(x, y, aov.position);
+        self.object_id.set(x, y, grey(aov.object_id as f64));
+    }
+}
+
+// Shape of the lens opening, which out-of-focus highlights take on. Samples are
+// scaled by the defocus radius.
+#[derive(Clone, Debug)]
+pub enum Aperture {
+    Disk,
+    // Regular polygon inscribed in the unit circle, rotated by rotation degrees
+    Polygon {
+        blades: u32,
+        rotation: f64,
+    },
+    // Grayscale mask spanning the defocus diameter, sampled in proportion to brightness
+    Image {
+        distribution: Distribution2D,
+        aspect: f64, // Mask width over height
+    },
+}
+impl Aperture {
+    fn sample(&self) -> (f64, f64) {
+        match self {
+            Aperture::Disk => {
+                let p = Vec3::random_in_unit_disk();
+                (p.e[0], p.e[1])
+            }
+            Aperture::Polygon { blades, rotation } => {
+                // Pick one of the equal triangles fanning out from the centre
+                let n = (*blades).max(3);
+                let k = random_int(0, n as i32 - 1) as f64;
+                let step = 2.0 * std::f64::consts::PI / n as f64;
+                let a0 = degrees_to_radians(*rotation) + k * step;
+                let a1 = a0 + step;
+                let (mut r1, mut r2) = (random_double_01(), random_double_01());
+                if r1 + r2 > 1.0 {
+                    (r1, r2) = (1.0 - r1, 1.0 - r2);
+                }
+                (r1 * a0.cos() + r2 * a1.cos(), r1 * a0.sin() + r2 * a1.sin())
+            }
+            Aperture::Image {
+                distribution,
+                aspect,
+            } => {
+                let (x, y) = distribution.sample(random_double_01(), random_double_01());
+                let (px, py) = (2.0 * x - 1.0, 1.0 - 2.0 * y);
+                if *aspect >= 1.0 {
+                    (px, py / aspect)
+                } else {
+                    (px * aspect, py)
+                }
+            }
+        }
+    }
+}
+
+// How pixels map to camera rays.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Projection {
+    Perspective,  // Thin lens with vfov, defocus_angle and focus_dist
+    Orthographic, // Parallel rays along -w through a viewport_height tall window
+    // Longitude across the width and latitude down the height, 360x180 degrees
+    Equirectangular,
+    // Circular fisheyes over the shorter image side covering fov degrees; equidistant
+    // keeps angle proportional to radius, equisolid keeps solid angle per area
+    FisheyeEquidistant,
+    FisheyeEquisolid,
+}
+
+// How the light and material samples of a bounce share the light both of them can
+// find (Veach 1997): in proportion to their pdfs, or to their squares, which leans
+// harder on whichever strategy suits the direction.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum, Serialize, Deserialize)]
+pub enum Mis {
+    Balance,
+    Power,
+}
+impl Mis {
+    // Weight of a sample drawn with density pdf when the other strategy has density other.
+    fn weight(self, pdf: f64, other: f64) -> f64 {
+        if pdf <= 0.0 {
+            return 0.0;
+        }
+        match self {
+            Mis::Balance => pdf / (pdf + other),
+            Mis::Power => pdf * pdf / (pdf * pdf + other * other),
+        }
+    }
+}
+
+// How much light the shutter lets through over its open interval.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShutterCurve {
+    Box,      // Fully open from open to close
+    Triangle, // Opens linearly to a peak halfway, then closes
+}
+impl ShutterCurve {
+    // Maps a uniform number in [0, 1) to a fraction of the open interval.
+    fn sample(&self, x: f64) -> f64 {
+        match self {
+            ShutterCurve::Box => x,
+            ShutterCurve::Triangle => {
+                if x < 0.5 {
+                    (x / 2.0).sqrt()
+                } else {
+                    1.0 - ((1.0 - x) / 2.0).sqrt()
+                }
+            }
+        }
+    }
+}
+
+pub struct RenderResult {
+    pub image: Framebuffer,
+    pub sample_counts: Vec<u32>, // Samples taken per pixel, same layout as image
+    pub aovs: Option<AovImages>,
+    pub stats: RenderStats,
+    pub region: Crop, // Part of the full frame the images cover
+}
+impl RenderResult {
+    // Puts the rendered region back into a black frame of the full image size.
+    pub fn into_full_frame(self, width: u32, height: u32) -> RenderResult {
+        let r = self.region;
+        let embed = |fb: &Framebuffer| fb.embed(r.x, r.y, width, height);
+        let mut sample_counts = vec![0; (width * height) as usize];
+        for (k, &n) in self.sample_counts.iter().enumerate() {
+            let (x, y) = (k as u32 % r.width, k as u32 / r.width);
+            sample_counts[((r.y + y) * width + r.x + x) as usize] = n;
+        }
+        RenderResult {
+            image: embed(&self.image),
+            sample_counts,
+            aovs: self.aovs.map(|a| AovImages {
+                albedo: embed(&a.albedo),
+                normal: embed(&a.normal),
+                depth: embed(&a.depth),
+                position: embed(&a.position),
+                object_id: embed(&a.object_id),
+            }),
+            stats: self.stats,
+            region: Crop {
+                x: 0,
+                y: 0,
+                width,
+                height,
+            },
+        }
+    }
+}
 
+#[derive(Clone)]
 pub struct Camera {
-    pub aspect_ratio: f64, // Ratio of image width over height
-    pub width: u32,        // Rendered image width in pixel count
+    pub aspect_ratio: f64,         // Ratio of image width over height
+    pub width: u32,                // Rendered image width in pixel count
+    pub fixed_height: Option<u32>, // Image height to use instead of one from aspect_ratio
     pub samples_per_pixel: u32,
-    pub max_depth: u32,
-    pub background: Color,
+    pub max_depth: u32, // Hard cap on path length; Russian roulette ends most paths first
+    pub rr_depth: u32,  // Bounces before Russian roulette starts
+    pub mis: Mis,
+    pub background: Environment,
+    pub delta_lights: Vec<DeltaLight>,
 
     pub vfov: f64,
     pub lookfrom: Point3,
@@ -37,11 +299,28 @@ pub struct Camera {
 
     pub defocus_angle: f64,
     pub focus_dist: f64,
+    pub aperture: Aperture,
+
+    // Ray times are drawn from [shutter_open, shutter_close) in scene time
+    pub shutter_open: f64,
+    pub shutter_close: f64,
+    pub shutter_curve: ShutterCurve,
+
+    pub projection: Projection,
+    pub viewport_height: f64, // World-space height of the orthographic view
+    pub fov: f64,             // Fisheye field of view in degrees
+
+    pub seed: Option<u64>, // Fixed seed for repeatable renders, random when None
+    pub crop: Option<Crop>,
+    pub adaptive: Option<Adaptive>,
+    pub aovs: bool, // Also collect the first-hit passes
 
     pub height: u32, // Rendered image height
+    pub sample_seed: u64,
     pub pixel_samples_scale: f64,
     pub sqrt_spp: u32,
     pub recip_sqrt_spp: f64,
+    pub passes: u32, // Stratified passes of sqrt_spp^2 samples per pixel
 
     pub camera_center: Point3, // Camera center
     pub pixel_loc: Point3,     // Location of pixel 0, 0
@@ -54,110 +333,317 @@ pub struct Camera {
     pub defocus_disk_v: Vec3,
 }
 impl Camera {
-    fn clone(&self) -> Camera {
+    pub fn new() -> Camera {
         Camera {
-            aspect_ratio: self.aspect_ratio, // Ratio of image width over height
-            width: self.width,               // Rendered image width in pixel count
-            samples_per_pixel: self.samples_per_pixel,
-            max_depth: self.max_depth,
-            background: self.background,
-
-            vfov: self.vfov,
-            lookfrom: self.lookfrom,
-            lookat: self.lookat,
-            vup: self.vup,
-
-            defocus_angle: self.defocus_angle,
-            focus_dist: self.focus_dist,
-
-            height: self.height,
-            pixel_samples_scale: self.pixel_samples_scale,
-            sqrt_spp: self.sqrt_spp,
-            recip_sqrt_spp: self.recip_sqrt_spp,
-            camera_center: self.camera_center,
-            pixel_loc: self.pixel_loc,
-            delta_u: self.delta_u,
-            delta_v: self.delta_v,
-            u: self.u,
-            v: self.v,
-            w: self.w,
-            defocus_disk_u: self.defocus_disk_u,
-            defocus_disk_v: self.defocus_disk_v,
-        }
-    }
-    fn ray_color(&self, r: &Ray, depth: i32, world: &HitObject, lights: &HitObject) -> Color {
-        if depth <= 0 {
-            return Color::new();
-        }
-        let (rec, flag) = world.hit(
-            r,
-            &Interval {
-                min: 0.001,
-                max: INF,
+            aspect_ratio: 1.0,
+            width: 100,
+            fixed_height: None,
+            samples_per_pixel: 10,
+            max_depth: 10,
+            rr_depth: 3,
+            mis: Mis::Power,
+            background: Environment::Solid {
+                color: Color::new(),
             },
-        );
-        if flag {
+            delta_lights: Vec::new(),
+
+            vfov: 90.0,
+            lookfrom: Point3::new(),
+            lookat: Point3 {
+                e: [0.0, 0.0, -1.0],
+            },
+            vup: Vec3 { e: [0.0, 1.0, 0.0] },
+
+            defocus_angle: 0.0,
+            focus_dist: 10.0,
+            aperture: Aperture::Disk,
+
+            shutter_open: 0.0,
+            shutter_close: 1.0,
+            shutter_curve: ShutterCurve::Box,
+
+            projection: Projection::Perspective,
+            viewport_height: 2.0,
+            fov: 180.0,
+
+            seed: None,
+            crop: None,
+            adaptive: None,
+            aovs: false,
+
+            height: 0,
+            sample_seed: 0,
+            pixel_samples_scale: 0.0,
+            sqrt_spp: 0,
+            recip_sqrt_spp: 0.0,
+            passes: 0,
+            camera_center: Vec3::new(),
+            pixel_loc: Vec3::new(),
+            delta_u: Vec3::new(),
+            delta_v: Vec3::new(),
+            u: Vec3::new(),
+            v: Vec3::new(),
+            w: Vec3::new(),
+            defocus_disk_u: Vec3::new(),
+            defocus_disk_v: Vec3::new(),
+        }
+    }
+    // Follows one camera path bounce by bounce, adding the emission found at each hit
+    // weighted by the throughput of the bounces before it. Every diffuse bounce also
+    // samples a direction towards the emitters, and emission reached both ways is
+    // shared between the two samples by the MIS heuristic.
+    fn ray_color(
+        &self,
+        r: &Ray,
+        world: &HitObject,
+        lights: &LightTree,
+        mut aov: Option<&mut Aov>,
+    ) -> Color {
+        let mut radiance = Color::new();
+        let mut throughput = Color { e: [1.0, 1.0, 1.0] };
+        let mut ray = *r;
+        // Material and emitter pdfs of the direction ray was scattered in; None for
+        // camera rays and specular bounces, whose emission is never sampled twice
+        let mut last_pdfs: Option<(f64, f64)> = None;
+        stats::count(|s| s.paths += 1);
+        for bounce in 0..self.max_depth {
+            stats::count(|s| {
+                s.path_segments += 1;
+                if bounce == 0 {
+                    s.primary_rays += 1;
+                } else {
+                    s.secondary_rays += 1;
+                }
+            });
+            let (rec, flag) = world.hit(
+                &ray,
+                &Interval {
+                    min: 0.001,
+                    max: INF,
+                },
+            );
+            if let Some(aov) = aov.take() {
+                *aov = if flag {
+                    Aov {
+                        albedo: rec.mat.albedo(&rec),
+                        normal: rec.normal,
+                        depth: rec.t * ray.dir.length(),
+                        position: rec.p,
+                        object_id: rec.object_id,
+                    }
+                } else {
+                    Aov {
+                        albedo: self.background.radiance(&ray.dir),
+                        ..Aov::new()
+                    }
+                };
+            }
+            if !flag {
+                let weight = self.material_weight(last_pdfs);
+                return radiance + throughput * self.background.radiance(&ray.dir) * weight;
+            }
+
             let mut srec = ScatterRecord {
                 attenuation: Color::new(),
-                pdf_ptr: Box::new(Pdf::Spherepdf),
+                pdf: Pdf::Spherepdf,
                 skip_pdf: false,
                 skip_pdf_ray: Ray::new(),
             };
-            let color_from_emission = rec.mat.emitted(r, &rec, rec.u, rec.v, &rec.p);
+            let emitted = rec.mat.emitted(&ray, &rec, rec.u, rec.v, &rec.p);
+            radiance = radiance + throughput * emitted * self.material_weight(last_pdfs);
+            if !rec.mat.scatter(&ray, &rec, &mut srec) {
+                return radiance;
+            }
 
-            if rec.mat.scatter(r, &rec, &mut srec) {
-                if srec.skip_pdf {
-                    return self.ray_color(&srec.skip_pdf_ray, depth - 1, world, lights)
-                        * srec.attenuation;
-                }
-                let light_ptr = Pdf::Hittablepdf {
-                    objects: Box::new(lights.clone()),
+            let (scattered, weight) = if srec.skip_pdf {
+                last_pdfs = None;
+                (srec.skip_pdf_ray, srec.attenuation)
+            } else {
+                let light_pdf = Pdf::Lightpdf {
+                    lights,
                     ori: rec.p,
+                    time: ray.tm,
                 };
-                let mixed_pdf = Pdf::Mixturepdf {
-                    p: [Box::new(light_ptr), srec.pdf_ptr],
+                let environment_pdf = Pdf::Environmentpdf {
+                    environment: &self.background,
                 };
+                let emitters_pdf = Pdf::Mixturepdf {
+                    p: [&light_pdf, &environment_pdf],
+                };
+                let emitters = match (lights.is_empty(), self.background.is_sampled()) {
+                    (false, true) => Some(&emitters_pdf),
+                    (false, false) => Some(&light_pdf),
+                    (true, true) => Some(&environment_pdf),
+                    (true, false) => None,
+                };
+                // The last bounce has no further segment for emitter samples to stand in for
+                if bounce + 1 < self.max_depth {
+                    if let Some(emitters) = emitters {
+                        let direct = self.sample_emitters(&ray, &rec, &srec, emitters, world);
+                        radiance = radiance + throughput * direct;
+                    }
+                    for light in &self.delta_lights {
+                        let direct = self.sample_delta_light(&ray, &rec, &srec, light, world);
+                        radiance = radiance + throughput * direct;
+                    }
+                }
 
                 let scattered = Ray {
                     ori: rec.p,
-                    dir: mixed_pdf.generate(),
-                    tm: r.tm,
+                    dir: srec.pdf.generate(),
+                    tm: ray.tm,
                 };
-                let pdf_val = mixed_pdf.value(scattered.dir);
-
-                let scattering_pdf = rec.mat.scattering_pdf(r, &rec, &scattered);
+                let material_pdf = srec.pdf.value(scattered.dir);
+                if material_pdf.is_nan() || material_pdf <= 0.0 {
+                    return radiance;
+                }
+                let emitter_pdf = emitters.map_or(0.0, |e| e.value(scattered.dir));
+                last_pdfs = Some((material_pdf, emitter_pdf));
+                let scattering_pdf = rec.mat.scattering_pdf(&ray, &rec, &scattered);
+                (scattered, srec.attenuation * scattering_pdf / material_pdf)
+            };
 
-                let color_from_scatter = (self.ray_color(&scattered, depth - 1, world, lights)
-                    * srec.attenuation
-                    * scattering_pdf)
-                    / pdf_val;
-                //    dbg!(color_from_emission);
-                //    dbg!(color_from_scatter);
-                return color_from_emission + color_from_scatter;
+            throughput = throughput * weight;
+            let survival = self.survival(bounce, &throughput);
+            if survival < 1.0 && random_double_01() >= survival {
+                return radiance;
             }
-            return color_from_emission;
+            throughput = throughput / survival;
+            ray = scattered;
         }
-        self.background
+        radiance
     }
-    fn initialize(&mut self) {
-        self.height = (self.width as f64 / self.aspect_ratio) as u32;
-        self.height = {
-            if self.height < 1 {
-                1
-            } else {
-                self.height
-            }
+    // Next-event estimate at a diffuse hit: light arriving along one direction drawn
+    // from the emitters, through the material, weighted against the material's own
+    // chance of picking that direction.
+    fn sample_emitters(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        emitters: &Pdf,
+        world: &HitObject,
+    ) -> Color {
+        let shadow_ray = Ray {
+            ori: rec.p,
+            dir: emitters.generate(),
+            tm: r_in.tm,
+        };
+        let emitter_pdf = emitters.value(shadow_ray.dir);
+        let scattering_pdf = rec.mat.scattering_pdf(r_in, rec, &shadow_ray);
+        // Nothing to gain from tracing towards the back of the surface
+        if emitter_pdf.is_nan() || emitter_pdf <= 0.0 || scattering_pdf <= 0.0 {
+            return Color::new();
+        }
+        stats::count(|s| s.shadow_rays += 1);
+        let (hit, flag) = world.hit(
+            &shadow_ray,
+            &Interval {
+                min: 0.001,
+                max: INF,
+            },
+        );
+        let emitted = if flag {
+            hit.mat.emitted(&shadow_ray, &hit, hit.u, hit.v, &hit.p)
+        } else {
+            self.background.radiance(&shadow_ray.dir)
         };
+        let material_pdf = srec.pdf.value(shadow_ray.dir);
+        let weight = self.mis.weight(emitter_pdf, material_pdf);
+        srec.attenuation * emitted * (scattering_pdf * weight / emitter_pdf)
+    }
+    // Light from a point, spot or directional light at a diffuse hit. The material can
+    // never sample such a light, so this is its only way in and needs no MIS weight.
+    fn sample_delta_light(
+        &self,
+        r_in: &Ray,
+        rec: &HitRecord,
+        srec: &ScatterRecord,
+        light: &DeltaLight,
+        world: &HitObject,
+    ) -> Color {
+        let (dir, distance, incoming) = light.sample(&rec.p);
+        let shadow_ray = Ray {
+            ori: rec.p,
+            dir,
+            tm: r_in.tm,
+        };
+        let scattering_pdf = rec.mat.scattering_pdf(r_in, rec, &shadow_ray);
+        if scattering_pdf <= 0.0 || incoming.e.iter().all(|&c| c <= 0.0) {
+            return Color::new();
+        }
+        stats::count(|s| s.shadow_rays += 1);
+        let (_, blocked) = world.hit(
+            &shadow_ray,
+            &Interval {
+                min: 0.001,
+                max: distance - 0.001,
+            },
+        );
+        if blocked {
+            return Color::new();
+        }
+        srec.attenuation * incoming * scattering_pdf
+    }
+    // MIS weight of emission that the material sample of the previous bounce ran into.
+    fn material_weight(&self, last_pdfs: Option<(f64, f64)>) -> f64 {
+        match last_pdfs {
+            Some((material_pdf, emitter_pdf)) => self.mis.weight(material_pdf, emitter_pdf),
+            None => 1.0,
+        }
+    }
+    // Chance that a path carrying throughput goes on after this bounce. Paths always
+    // survive the first rr_depth bounces; after that, dim paths are cut at random and
+    // the survivors weighted up by 1/survival, which keeps the estimate unbiased.
+    fn survival(&self, bounce: u32, throughput: &Color) -> f64 {
+        if bounce < self.rr_depth {
+            return 1.0;
+        }
+        let max = throughput.e[0].max(throughput.e[1]).max(throughput.e[2]);
+        if max.is_nan() {
+            return 0.0;
+        }
+        max.clamp(0.0, 1.0)
+    }
+    // Height of the rendered image, at least one pixel.
+    pub fn image_height(&self) -> u32 {
+        let height = match self.fixed_height {
+            Some(height) => height,
+            None => (self.width as f64 / self.aspect_ratio) as u32,
+        };
+        height.max(1)
+    }
+    fn initialize(&mut self) {
+        self.height = self.image_height();
+
+        self.sample_seed = self.seed.unwrap_or_else(rand::random);
 
-        self.sqrt_spp = (self.samples_per_pixel as f64).sqrt() as u32;
+        match self.adaptive {
+            Some(adaptive) => {
+                self.sqrt_spp = ((adaptive.min_spp as f64).sqrt() as u32).max(1);
+                let per_pass = self.sqrt_spp * self.sqrt_spp;
+                self.passes = ((self.samples_per_pixel + per_pass - 1) / per_pass).max(1);
+            }
+            None => {
+                self.sqrt_spp = (self.samples_per_pixel as f64).sqrt() as u32;
+                self.passes = 1;
+            }
+        }
         self.pixel_samples_scale = 1.0 / (self.sqrt_spp * self.sqrt_spp) as f64;
         self.recip_sqrt_spp = 1.0 / self.sqrt_spp as f64;
 
         self.camera_center = self.lookfrom;
 
-        let theta = degrees_to_radians(self.vfov);
-        let h = (theta / 2.0).tan();
-        let viewport_height = 2.0 * h * self.focus_dist;
+        let viewport_height = match self.projection {
+            Projection::Perspective => {
+                let theta = degrees_to_radians(self.vfov);
+                let h = (theta / 2.0).tan();
+                2.0 * h * self.focus_dist
+            }
+            Projection::Orthographic => self.viewport_height,
+            // Panoramic rays are built from u, v, w directly
+            _ => 2.0,
+        };
         let viewport_width = viewport_height * (self.width as f64 / self.height as f64);
 
         self.w = Vec3::unit_vector(self.lookfrom - self.lookat);
@@ -179,25 +665,86 @@ impl Camera {
         self.defocus_disk_u = self.u * defocus_radius;
         self.defocus_disk_v = self.v * defocus_radius;
     }
-    fn get_ray(&self, i: u32, j: u32, si: u32, sj: u32) -> Ray {
+    // None for pixels that a fisheye does not cover.
+    fn get_ray(&self, i: u32, j: u32, si: u32, sj: u32) -> Option<Ray> {
         let offset = self.sample_square_stratified(si, sj);
+        match self.projection {
+            Projection::Equirectangular
+            | Projection::FisheyeEquidistant
+            | Projection::FisheyeEquisolid => {
+                let x = i as f64 + 0.5 + offset.e[0];
+                let y = j as f64 + 0.5 + offset.e[1];
+                return self.panoramic_direction(x, y).map(|dir| Ray {
+                    ori: self.camera_center,
+                    dir,
+                    tm: self.sample_time(),
+                });
+            }
+            Projection::Perspective | Projection::Orthographic => (),
+        }
         let pixel_sample = self.pixel_loc
             + (self.delta_u * (i as f64 + offset.e[0]))
             + (self.delta_v * (j as f64 + offset.e[1]));
+        if self.projection == Projection::Orthographic {
+            // Start on the plane through lookfrom, so objects behind the viewport still show
+            return Some(Ray {
+                ori: pixel_sample + self.w * self.focus_dist,
+                dir: -self.w * self.focus_dist,
+                tm: self.sample_time(),
+            });
+        }
         let ray_origin = if self.defocus_angle <= 0.0 {
             self.camera_center
         } else {
             self.defocus_disk_sample()
         };
-        Ray {
+        Some(Ray {
             ori: ray_origin,
             dir: pixel_sample - ray_origin,
-            tm: random_double_01(),
+            tm: self.sample_time(),
+        })
+    }
+    // Unit view direction through image point (x, y) in pixels, looking along -w at
+    // the image centre.
+    fn panoramic_direction(&self, x: f64, y: f64) -> Option<Vec3> {
+        let pi = std::f64::consts::PI;
+        let (width, height) = (self.width as f64, self.height as f64);
+        if self.projection == Projection::Equirectangular {
+            let phi = (x / width - 0.5) * 2.0 * pi;
+            let theta = (0.5 - y / height) * pi;
+            return Some(
+                self.u * (theta.cos() * phi.sin()) + self.v * theta.sin()
+                    - self.w * (theta.cos() * phi.cos()),
+            );
         }
+
+        let radius = 0.5 * width.min(height);
+        let px = (x - 0.5 * width) / radius;
+        let py = (0.5 * height - y) / radius;
+        let r = (px * px + py * py).sqrt();
+        if r > 1.0 {
+            return None;
+        }
+        let half_fov = degrees_to_radians(self.fov) / 2.0;
+        let theta = if self.projection == Projection::FisheyeEquidistant {
+            r * half_fov
+        } else {
+            2.0 * (r * (half_fov / 2.0).sin()).min(1.0).asin()
+        };
+        let (dx, dy) = if r > 0.0 {
+            (px / r, py / r)
+        } else {
+            (0.0, 0.0)
+        };
+        Some((self.u * dx + self.v * dy) * theta.sin() - self.w * theta.cos())
+    }
+    fn sample_time(&self) -> f64 {
+        let fraction = self.shutter_curve.sample(random_double_01());
+        self.shutter_open + (self.shutter_close - self.shutter_open) * fraction
     }
     fn defocus_disk_sample(&self) -> Point3 {
-        let p = Vec3::random_in_unit_disk();
-        self.camera_center + (self.defocus_disk_u * p.e[0]) + (self.defocus_disk_v * p.e[1])
+        let (x, y) = self.aperture.sample();
+        self.camera_center + (self.defocus_disk_u * x) + (self.defocus_disk_v * y)
     }
 
     fn sample_square_stratified(&self, si: u32, sj: u32) -> Vec3 {
@@ -229,76 +776,327 @@ impl Camera {
         }
         progress.finish();
     }*/
-    fn render_block(
+    // Returns the pixel's mean radiance, the number of samples it took and its
+    // averaged passes (left empty unless aovs is set).
+    fn render_pixel(
         &self,
-        world: HitObject,
-        lights: HitObject,
-        start_y: u32,
-        end_y: u32,
-    ) -> Vec<(u32, u32, Color)> {
-        let mut result = Vec::new();
-        for j in start_y..end_y {
-            for i in 0..self.width {
-                let mut pixel_color = Color::new();
-                for sj in 0..self.sqrt_spp {
-                    for si in 0..self.sqrt_spp {
-                        let r = self.get_ray(i, j, si, sj);
-                        pixel_color = pixel_color
-                            + self.ray_color(&r, self.max_depth as i32, &world, &lights);
+        i: u32,
+        j: u32,
+        world: &HitObject,
+        lights: &LightTree,
+    ) -> (Color, u32, Aov) {
+        let pixel = j as u64 * self.width as u64 + i as u64;
+        let mut pixel_color = Color::new();
+        let mut pixel_aov = Aov::new();
+        let mut n = 0;
+        // Running mean and squared deviation of the sample luminance (Welford)
+        let mut mean = 0.0;
+        let mut m2 = 0.0;
+        let per_pass = self.sqrt_spp * self.sqrt_spp;
+        // Adaptive passes stop at samples_per_pixel, which may end partway into one
+        let cap = match self.adaptive {
+            Some(_) => self.samples_per_pixel.max(1),
+            None => per_pass,
+        };
+        for pass in 0..self.passes {
+            let partial = cap - n < per_pass;
+            for k in 0..per_pass.min(cap - n) {
+                seed_sample(self.sample_seed, pixel, n as u64);
+                // A pass cut short would leave the last strata out, so it jitters
+                // over random strata instead
+                let (si, sj) = if partial {
+                    (
+                        random_int(0, self.sqrt_spp as i32 - 1) as u32,
+                        random_int(0, self.sqrt_spp as i32 - 1) as u32,
+                    )
+                } else {
+                    (k % self.sqrt_spp, k / self.sqrt_spp)
+                };
+                let c = match self.get_ray(i, j, si, sj) {
+                    None => Color::new(),
+                    Some(r) if self.aovs => {
+                        let mut aov = Aov::new();
+                        let c = self.ray_color(&r, world, lights, Some(&mut aov));
+                        pixel_aov.albedo = pixel_aov.albedo + aov.albedo;
+                        pixel_aov.normal = pixel_aov.normal + aov.normal;
+                        pixel_aov.depth += aov.depth;
+                        pixel_aov.position = pixel_aov.position + aov.position;
+                        if n == 0 {
+                            pixel_aov.object_id = aov.object_id;
+                        }
+                        c
                     }
+                    Some(r) => self.ray_color(&r, world, lights, None),
+                };
+                pixel_color = pixel_color + c;
+                n += 1;
+
+                let y = luminance(&c);
+                if y.is_finite() {
+                    let delta = y - mean;
+                    mean += delta / n as f64;
+                    m2 += delta * (y - mean);
+                }
+            }
+            // A single pass can be a single sample, whose spread says nothing
+            if let Some(adaptive) = self.adaptive.filter(|_| pass >= 1) {
+                let std_error = (m2 / ((n - 1).max(1) as f64) / n as f64).sqrt();
+                if std_error / (2.0 * mean.max(0.0).sqrt() + 1e-4) < adaptive.threshold {
+                    break;
                 }
-                result.push((i, j, pixel_color * self.pixel_samples_scale));
+            }
+        }
+        let scale = 1.0 / n as f64;
+        pixel_aov.albedo = pixel_aov.albedo * scale;
+        pixel_aov.normal = pixel_aov.normal * scale;
+        pixel_aov.depth *= scale;
+        pixel_aov.position = pixel_aov.position * scale;
+        (pixel_color * scale, n, pixel_aov)
+    }
+    // Renders one tile, returning its pixels, sample counts and passes row by row.
+    fn render_tile(&self, world: &HitObject, lights: &LightTree, tile: Crop) -> TilePixels {
+        let mut result = Vec::with_capacity((tile.width * tile.height) as usize);
+        for j in tile.y..tile.y + tile.height {
+            for i in tile.x..tile.x + tile.width {
+                result.push(self.render_pixel(i, j, world, lights));
             }
         }
         result
     }
+    // Splits the region into TILE_SIZE squares, row by row; edge tiles are smaller.
+    fn tiles(region: Crop) -> Vec<Crop> {
+        let mut tiles = Vec::new();
+        for y in (region.y..region.y + region.height).step_by(TILE_SIZE as usize) {
+            for x in (region.x..region.x + region.width).step_by(TILE_SIZE as usize) {
+                tiles.push(Crop {
+                    x,
+                    y,
+                    width: TILE_SIZE.min(region.x + region.width - x),
+                    height: TILE_SIZE.min(region.y + region.height - y),
+                });
+            }
+        }
+        tiles
+    }
+    // The crop window clipped to the image; the whole frame when there is none.
+    fn region(&self) -> Crop {
+        match self.crop {
+            Some(crop) => {
+                let x = crop.x.min(self.width);
+                let y = crop.y.min(self.height);
+                Crop {
+                    x,
+                    y,
+                    width: crop.width.min(self.width - x),
+                    height: crop.height.min(self.height - y),
+                }
+            }
+            None => Crop {
+                x: 0,
+                y: 0,
+                width: self.width,
+                height: self.height,
+            },
+        }
+    }
+    // Renders the crop window, or the whole frame. With a checkpoint, tiles it already
+    // holds are taken from it and every newly finished tile is saved to it.
     pub fn render(
         &mut self,
         world: HitObject,
-        lights: HitObject,
-        file: &mut File,
+        lights: LightTree,
         num_threads: u32,
-    ) {
+        mut checkpoint: Option<&mut Checkpoint>,
+    ) -> RenderResult {
+        let start = Instant::now();
         self.initialize();
+        let region = self.region();
+        let mut tiles = Self::tiles(region);
+        let done = match &mut checkpoint {
+            Some(checkpoint) => checkpoint.take_done(),
+            None => Vec::new(),
+        };
+        tiles.retain(|t| !done.iter().any(|(d, _)| (d.x, d.y) == (t.x, t.y)));
+        let progress = ProgressBar::new((tiles.len() + done.len()) as u64);
+        let tiles = Arc::new(tiles);
+        let next_tile = Arc::new(AtomicUsize::new(0));
         let (tx, rx) = channel();
-        let total_pixels = self.height * self.width;
-        let progress = ProgressBar::new(total_pixels as u64);
-
-        let block_height = self.height / num_threads;
 
-        for i in 0..num_threads {
+        // Every thread keeps pulling the next unrendered tile, so threads that land on
+        // cheap parts of the image keep helping until the whole queue is drained.
+        for _ in 0..num_threads {
             let world = world.clone();
             let lights = lights.clone();
-            let start_y = i * block_height;
-            let end_y = if i == num_threads - 1 {
-                self.height
-            } else {
-                (i + 1) * block_height
-            };
+            let tiles = Arc::clone(&tiles);
+            let next_tile = Arc::clone(&next_tile);
 
             let cam = self.clone();
             let tx1 = tx.clone();
 
-            thread::spawn(move || {
-                tx1.send(cam.render_block(world, lights, start_y, end_y))
-                    .unwrap();
+            thread::spawn(move || loop {
+                let index = next_tile.fetch_add(1, Ordering::Relaxed);
+                if index >= tiles.len() {
+                    break;
+                }
+                let tile = tiles[index];
+                let start = Instant::now();
+                let pixels = cam.render_tile(&world, &lights, tile);
+                let mut tile_stats = stats::take();
+                let seconds = start.elapsed().as_secs_f64();
+                tile_stats.tiles = 1;
+                tile_stats.tile_seconds = seconds;
+                tile_stats.slowest_tile_seconds = seconds;
+                tile_stats.tile_times = vec![TileTime {
+                    x: tile.x,
+                    y: tile.y,
+                    seconds,
+                }];
+                tx1.send((tile, pixels, tile_stats)).unwrap();
             });
         }
 
         drop(tx);
 
-        writeln!(file, "P3\n{} {}\n255", self.width, self.height).unwrap();
-
-        let mut result = vec![Color::new(); total_pixels as usize];
-        for block_result in rx {
-            for (i, j, c) in block_result {
-                result[(j * self.width + i) as usize] = c;
+        let mut image = Framebuffer::new(region.width, region.height);
+        let mut sample_counts = vec![0; (region.width * region.height) as usize];
+        let mut aovs = if self.aovs {
+            Some(AovImages::new(region.width, region.height))
+        } else {
+            None
+        };
+        let mut render_stats = RenderStats::default();
+        let resumed = done.into_iter().map(|(tile, pixels)| (tile, pixels, None));
+        let rendered = rx.into_iter().map(|(tile, pixels, tile_stats)| {
+            if let Some(checkpoint) = &mut checkpoint {
+                checkpoint.save(tile, &pixels, self.aovs);
+            }
+            (tile, pixels, Some(tile_stats))
+        });
+        for (tile, pixels, tile_stats) in resumed.chain(rendered) {
+            if let Some(tile_stats) = tile_stats {
+                render_stats.merge(&tile_stats);
+            }
+            for j in 0..tile.height {
+                for i in 0..tile.width {
+                    let (x, y) = (tile.x - region.x + i, tile.y - region.y + j);
+                    let (c, n, aov) = pixels[(j * tile.width + i) as usize];
+                    image.set(x, y, c);
+                    sample_counts[(y * region.width + x) as usize] = n;
+                    if let Some(aovs) = &mut aovs {
+                        aovs.set(x, y, &aov);
+                    }
+                }
             }
-        }
-        for color in &result {
-            write_color(color, file);
             progress.inc(1);
         }
         progress.finish();
+        render_stats.finish(start.elapsed().as_secs_f64());
+        RenderResult {
+            image,
+            sample_counts,
+            aovs,
+            stats: render_stats,
+            region,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::scene::{self, Scene};
+
+    fn render_pixels(scene: Scene, threads: u32) -> Vec<[f64; 3]> {
+        let mut cam = scene.camera;
+        let result = cam.render(scene.world, scene.lights, threads, None);
+        result.image.pixels.iter().map(|c| c.e).collect()
+    }
+
+    // Every surface reflects all light under a uniformly white sky, so every pixel
+    // comes out at 1 unless the renderer loses or invents energy. The sphere and the
+    // floor fill the frame and light bounces between them.
+    #[test]
+    fn white_furnace_renders_white() {
+        let src = r#"
+            [camera]
+            width = 8
+            aspect_ratio = 1.0
+            samples_per_pixel = 16
+            max_depth = 200
+            background = [1.0, 1.0, 1.0]
+            lookfrom = [0.0, 0.5, 2.5]
+            lookat = [0.0, -0.2, 0.0]
+            vfov = 40.0
+
+            [materials.white]
+            type = "lambertian"
+            color = [1.0, 1.0, 1.0]
+
+            [[objects]]
+            type = "sphere"
+            center = [0.0, 0.0, 0.0]
+            radius = 1.0
+            material = "white"
+
+            [[objects]]
+            type = "quad"
+            q = [-5.0, -1.0, -5.0]
+            u = [10.0, 0.0, 0.0]
+            v = [0.0, 0.0, 10.0]
+            material = "white"
+        "#;
+        let mut scene = scene::parse(src, "furnace", PathBuf::new()).unwrap();
+        scene.camera.seed = Some(1);
+        let pixels = render_pixels(scene, 1);
+        let mean = pixels.iter().flatten().sum::<f64>() / (pixels.len() * 3) as f64;
+        assert!((mean - 1.0).abs() < 0.02, "furnace mean is {}", mean);
+    }
+
+    // The image may only depend on the seed, never on how tiles were spread over
+    // threads or the order they finished in.
+    #[test]
+    fn same_seed_gives_the_same_image_on_any_thread_count() {
+        let render = |threads| {
+            let path = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("scenes/cornell.toml");
+            let mut scene = scene::load(path).unwrap();
+            scene.camera.width = 72;
+            scene.camera.fixed_height = Some(40);
+            scene.camera.samples_per_pixel = 4;
+            scene.camera.seed = Some(42);
+            render_pixels(scene, threads)
+        };
+        let one = render(1);
+        assert!(one.iter().flatten().any(|&c| c > 0.0));
+        assert!(one == render(4));
+    }
+
+    #[test]
+    fn mis_weights_of_both_strategies_sum_to_one() {
+        let pdfs = [1e-6, 0.01, 0.3, 1.0, 2.5, 40.0, 1e5];
+        for mis in [Mis::Balance, Mis::Power] {
+            for &a in &pdfs {
+                for &b in &pdfs {
+                    let sum = mis.weight(a, b) + mis.weight(b, a);
+                    assert!(
+                        (sum - 1.0).abs() < 1e-12,
+                        "{:?} weights for pdfs {} and {} sum to {}",
+                        mis,
+                        a,
+                        b,
+                        sum
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn mis_gives_everything_to_the_only_strategy_that_can_sample() {
+        for mis in [Mis::Balance, Mis::Power] {
+            assert_eq!(mis.weight(0.7, 0.0), 1.0);
+            assert_eq!(mis.weight(0.0, 0.7), 0.0);
+        }
     }
 }
@@ -0,0 +1,224 @@
+use std::error::Error;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::camera::{Aov, Crop, Mis, TilePixels};
+use crate::rtweekend::vec3::{Color, Vec3};
+
+// Finished tiles of a render kept on disk, so that an interrupted render can pick up
+// where it stopped. The file holds one JSON line describing the render followed by
+// one line per tile, appended as tiles come in; a line cut short by a crash is
+// dropped when the file is opened again.
+pub struct Checkpoint {
+    file: File,
+    seed: u64,
+    done: Vec<(Crop, TilePixels)>,
+}
+
+// Everything that has to match for saved tiles to belong to the render being resumed.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Settings {
+    pub scene: String,
+    pub scene_hash: u64, // Of the scene file, so editing it starts the render over
+    pub frame: Option<u32>,
+    pub width: u32,
+    pub aspect_ratio: f64,
+    pub samples_per_pixel: u32,
+    pub max_depth: u32,
+    pub rr_depth: u32,
+    pub mis: Mis,
+    pub adaptive: Option<(u32, f64)>,
+    pub crop: Option<[u32; 4]>,
+    pub aovs: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Header {
+    settings: Settings,
+    seed: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct TileRecord {
+    tile: [u32; 4],
+    color: Vec<[f64; 3]>,
+    samples: Vec<u32>,
+    aovs: Vec<[f64; 11]>, // albedo, normal, depth, position, object id; empty without passes
+}
+
+// FNV-1a, which unlike std's hasher stays the same across builds.
+pub fn hash_file(path: &Path) -> io::Result<u64> {
+    Ok(fs::read(path)?.iter().fold(0xcbf29ce484222325, |h, &b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    }))
+}
+
+// The seed saved in the checkpoint at path, if there is one there. The scene has to
+// be loaded with it too, since building it draws random numbers of its own.
+pub fn saved_seed(path: &Path) -> Result<Option<u64>, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(None);
+    }
+    let text = fs::read_to_string(path)?;
+    let first = text.lines().next().unwrap_or_default();
+    let header: Header = serde_json::from_str(first)
+        .map_err(|_| format!("{}: not a render checkpoint", path.display()))?;
+    Ok(Some(header.seed))
+}
+
+impl Checkpoint {
+    // Opens the checkpoint at path, or starts a new one there. A fresh checkpoint
+    // uses seed, or a random one; an existing one keeps its own, so the resumed
+    // tiles get the samples they would have had in one go.
+    pub fn open(
+        path: &Path,
+        settings: Settings,
+        seed: Option<u64>,
+    ) -> Result<Checkpoint, Box<dyn Error>> {
+        if !path.exists() {
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let seed = seed.unwrap_or_else(rand::random);
+            let mut file = File::create(path)?;
+            let header = serde_json::to_string(&Header { settings, seed })?;
+            writeln!(file, "{}", header)?;
+            return Ok(Checkpoint {
+                file,
+                seed,
+                done: Vec::new(),
+            });
+        }
+
+        let text = fs::read_to_string(path)?;
+        let mut lines = text.split_inclusive('\n');
+        let first = lines.next().unwrap_or_default();
+        let header: Header = serde_json::from_str(first)
+            .map_err(|_| format!("{}: not a render checkpoint", path.display()))?;
+        if header.settings != settings {
+            return Err(format!(
+                "{}: checkpoint belongs to a different render, remove it to start over",
+                path.display()
+            )
+            .into());
+        }
+        if seed.map_or(false, |seed| seed != header.seed) {
+            return Err(format!(
+                "{}: checkpoint was rendered with seed {}",
+                path.display(),
+                header.seed
+            )
+            .into());
+        }
+
+        let mut valid = first.len();
+        let mut done = Vec::new();
+        for line in lines {
+            let record: TileRecord = match serde_json::from_str(line) {
+                Ok(record) if line.ends_with('\n') => record,
+                _ => break,
+            };
+            done.push(record.into_tile());
+            valid += line.len();
+        }
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        file.set_len(valid as u64)?;
+        file.seek(SeekFrom::End(0))?;
+        Ok(Checkpoint {
+            file,
+            seed: header.seed,
+            done,
+        })
+    }
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+    // Tiles saved by earlier runs; each is handed out once.
+    pub fn take_done(&mut self) -> Vec<(Crop, TilePixels)> {
+        std::mem::take(&mut self.done)
+    }
+    pub fn save(&mut self, tile: Crop, pixels: &[(Color, u32, Aov)], aovs: bool) {
+        let record = TileRecord::new(tile, pixels, aovs);
+        // Losing a tile only costs rendering it again, so a failed write just warns
+        let result = serde_json::to_string(&record)
+            .map_err(io::Error::from)
+            .and_then(|line| self.file.write_all(format!("{}\n", line).as_bytes()));
+        if let Err(e) = result {
+            eprintln!("warning: cannot save tile to checkpoint: {}", e);
+        }
+    }
+}
+
+impl TileRecord {
+    fn new(tile: Crop, pixels: &[(Color, u32, Aov)], aovs: bool) -> TileRecord {
+        let aovs = if aovs {
+            pixels
+                .iter()
+                .map(|(_, _, a)| {
+                    let (al, n, p) = (a.albedo.e, a.normal.e, a.position.e);
+                    [
+                        al[0],
+                        al[1],
+                        al[2],
+                        n[0],
+                        n[1],
+                        n[2],
+                        a.depth,
+                        p[0],
+                        p[1],
+                        p[2],
+                        a.object_id as f64,
+                    ]
+                })
+                .collect()
+        } else {
+            Vec::new()
+        };
+        TileRecord {
+            tile: [tile.x, tile.y, tile.width, tile.height],
+            color: pixels.iter().map(|(c, _, _)| c.e).collect(),
+            samples: pixels.iter().map(|(_, n, _)| *n).collect(),
+            aovs,
+        }
+    }
+    fn into_tile(self) -> (Crop, TilePixels) {
+        let [x, y, width, height] = self.tile;
+        let pixels = self
+            .color
+            .iter()
+            .zip(&self.samples)
+            .enumerate()
+            .map(|(k, (&e, &n))| {
+                let aov = match self.aovs.get(k) {
+                    Some(a) => Aov {
+                        albedo: Color {
+                            e: [a[0], a[1], a[2]],
+                        },
+                        normal: Vec3 {
+                            e: [a[3], a[4], a[5]],
+                        },
+                        depth: a[6],
+                        position: Vec3 {
+                            e: [a[7], a[8], a[9]],
+                        },
+                        object_id: a[10] as u32,
+                    },
+                    None => Aov::new(),
+                };
+                (Color { e }, n, aov)
+            })
+            .collect();
+        (
+            Crop {
+                x,
+                y,
+                width,
+                height,
+            },
+            pixels,
+        )
+    }
+}
@@ -0,0 +1,596 @@
+use std::error::Error;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use clap::{Args, Parser, Subcommand};
+
+use crate::camera::{Adaptive, AovImages, Crop, Mis};
+use crate::checkpoint::{self, Checkpoint, Settings};
+use crate::denoise;
+use crate::hittable_list::HitObject;
+use crate::output::{self, Format, Framebuffer};
+use crate::rtw_image::load_hdr_image;
+use crate::rtweekend::color::ToneMap;
+use crate::rtweekend::seed_random;
+use crate::scene::{self, Scene};
+use crate::stats;
+
+const DEFAULT_SCENE: &str = "raytracer/scenes/final.toml";
+const DEFAULT_OUTPUT: &str = "output/book1/image10.ppm";
+
+#[derive(Parser)]
+#[command(name = "raytracer", about = "Path tracer for TOML scene files")]
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Render a scene to an image file
+    Render(Box<RenderArgs>),
+    /// Print statistics about a scene without rendering it
+    Info {
+        #[arg(default_value = DEFAULT_SCENE)]
+        scene: PathBuf,
+    },
+    /// Compare two images and print their difference
+    Compare(CompareArgs),
+}
+
+#[derive(Args)]
+pub struct RenderArgs {
+    /// Scene description file
+    #[arg(default_value = DEFAULT_SCENE)]
+    pub scene: PathBuf,
+    /// Output image path
+    #[arg(short, long, default_value = DEFAULT_OUTPUT)]
+    pub output: PathBuf,
+    /// Output image format [default: from the output extension]
+    #[arg(long, value_enum)]
+    pub format: Option<Format>,
+    /// Image width in pixels, overrides the scene
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub width: Option<u32>,
+    /// Image height in pixels, overrides the scene's aspect ratio
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub height: Option<u32>,
+    /// Samples per pixel, overrides the scene
+    #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+    pub spp: Option<u32>,
+    /// Maximum path depth, overrides the scene
+    #[arg(long)]
+    pub max_depth: Option<u32>,
+    /// Bounces before Russian roulette may end a path, overrides the scene
+    #[arg(long)]
+    pub rr_depth: Option<u32>,
+    /// How light and material samples are weighted against each other, overrides the scene
+    #[arg(long, value_enum)]
+    pub mis: Option<Mis>,
+    /// Number of render threads [default: available cores]
+    #[arg(short = 'j', long)]
+    pub threads: Option<u32>,
+    /// Seed for the random generators, for repeatable renders
+    #[arg(long)]
+    pub seed: Option<u64>,
+    /// Only render the pixel rectangle X,Y,W,H of the full frame
+    #[arg(long, value_name = "X,Y,W,H", value_parser = parse_crop)]
+    pub crop: Option<Crop>,
+    /// Write a --crop render at the full frame size with the rest left black
+    #[arg(long)]
+    pub full_frame: bool,
+    /// Save finished tiles to PATH and resume from it when restarted with the same
+    /// settings; removed once the image is written
+    #[arg(long, value_name = "PATH")]
+    pub checkpoint: Option<PathBuf>,
+    /// Sample adaptively until the per-pixel error is below THRESHOLD, with --spp as the cap
+    #[arg(long, value_name = "THRESHOLD")]
+    pub adaptive: Option<f64>,
+    /// Samples every pixel gets before the adaptive error check; a square number
+    #[arg(long, default_value_t = 16)]
+    pub min_spp: u32,
+    /// Also write a heatmap of the samples taken per pixel
+    #[arg(long, value_name = "PATH")]
+    pub sample_heatmap: Option<PathBuf>,
+    /// Also write albedo, normal, depth, position and object id passes, as extra
+    /// layers of an EXR output or as NAME.PASS.EXT files next to it otherwise
+    #[arg(long)]
+    pub aovs: bool,
+    /// Denoise the image guided by the albedo, normal and depth passes; STRENGTH
+    /// scales how aggressively colours are averaged
+    #[arg(long, value_name = "STRENGTH", num_args = 0..=1, default_missing_value = "1.0")]
+    pub denoise: Option<f64>,
+    /// Tone curve for PPM and PNG output; float formats stay linear
+    #[arg(long, value_enum, default_value_t = ToneMap::Clamp)]
+    pub tonemap: ToneMap,
+    /// Exposure adjustment in stops, applied before the tone curve; float formats are
+    /// scaled by it too
+    #[arg(long, default_value_t = 0.0, allow_negative_numbers = true)]
+    pub exposure: f64,
+    /// Luminance mapped to white by reinhard-extended [default: brightest pixel]
+    #[arg(long)]
+    pub white: Option<f64>,
+    /// Frames of an animated scene to render, each written as NAME_0001.EXT and so on
+    /// [default: the scene's frame range]
+    #[arg(long, value_name = "FIRST-LAST", value_parser = parse_frames)]
+    pub frames: Option<(u32, u32)>,
+    /// Print ray, traversal and timing statistics to stderr after rendering
+    #[arg(long)]
+    pub stats: bool,
+    /// Also write the statistics as JSON to PATH
+    #[arg(long, value_name = "PATH")]
+    pub stats_json: Option<PathBuf>,
+}
+impl RenderArgs {
+    fn new() -> RenderArgs {
+        RenderArgs {
+            scene: PathBuf::from(DEFAULT_SCENE),
+            output: PathBuf::from(DEFAULT_OUTPUT),
+            format: None,
+            width: None,
+            height: None,
+            spp: None,
+            max_depth: None,
+            rr_depth: None,
+            mis: None,
+            threads: None,
+            seed: None,
+            crop: None,
+            full_frame: false,
+            checkpoint: None,
+            adaptive: None,
+            min_spp: 16,
+            sample_heatmap: None,
+            aovs: false,
+            denoise: None,
+            tonemap: ToneMap::Clamp,
+            exposure: 0.0,
+            white: None,
+            frames: None,
+            stats: false,
+            stats_json: None,
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct CompareArgs {
+    pub first: PathBuf,
+    pub second: PathBuf,
+    /// Exit with an error when the RMSE is above this value
+    #[arg(long)]
+    pub threshold: Option<f64>,
+}
+
+fn parse_crop(s: &str) -> Result<Crop, String> {
+    let v: Vec<u32> = s
+        .split(',')
+        .map(|x| x.trim().parse::<u32>())
+        .collect::<Result<_, _>>()
+        .map_err(|e| e.to_string())?;
+    if v.len() != 4 {
+        return Err("expected four values X,Y,W,H".to_string());
+    }
+    if v[2] == 0 || v[3] == 0 {
+        return Err("width and height must be at least 1".to_string());
+    }
+    Ok(Crop {
+        x: v[0],
+        y: v[1],
+        width: v[2],
+        height: v[3],
+    })
+}
+
+fn parse_frames(s: &str) -> Result<(u32, u32), String> {
+    let parse = |x: &str| x.trim().parse::<u32>().map_err(|e| e.to_string());
+    let (first, last) = match s.split_once('-') {
+        Some((a, b)) => (parse(a)?, parse(b)?),
+        None => (parse(s)?, parse(s)?),
+    };
+    if first > last {
+        return Err(format!(
+            "first frame {} is after last frame {}",
+            first, last
+        ));
+    }
+    Ok((first, last))
+}
+
+// The seed of the checkpoint at path or, for animations, of any frame's checkpoint
+// next to it; every frame is rendered with the same one.
+fn checkpoint_seed(path: &Path) -> Result<Option<u64>, Box<dyn Error>> {
+    if let Some(seed) = checkpoint::saved_seed(path)? {
+        return Ok(Some(seed));
+    }
+    let dir = match path.parent() {
+        Some(dir) if dir.as_os_str().is_empty() => Path::new("."),
+        Some(dir) => dir,
+        None => return Ok(None),
+    };
+    let frame_name = frame_path(path, 0);
+    let frame_name = frame_name.file_name().unwrap_or_default().to_string_lossy();
+    let (prefix, suffix) = frame_name.rsplit_once("0000").unwrap_or_default();
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(_) => return Ok(None),
+    };
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().to_string();
+        let digits = name
+            .strip_prefix(prefix)
+            .and_then(|rest| rest.strip_suffix(suffix));
+        if digits.map_or(false, |d| {
+            d.len() == 4 && d.bytes().all(|b| b.is_ascii_digit())
+        }) {
+            return checkpoint::saved_seed(&dir.join(&name));
+        }
+    }
+    Ok(None)
+}
+
+// image.png becomes image_0007.png for frame 7.
+fn frame_path(path: &Path, frame: u32) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    match path.extension() {
+        Some(ext) => {
+            path.with_file_name(format!("{}_{:04}.{}", stem, frame, ext.to_string_lossy()))
+        }
+        None => path.with_file_name(format!("{}_{:04}", stem, frame)),
+    }
+}
+
+pub fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    match cli.command {
+        None => render(RenderArgs::new()),
+        Some(Command::Render(args)) => render(*args),
+        Some(Command::Info { scene }) => info(&scene),
+        Some(Command::Compare(args)) => compare(&args),
+    }
+}
+
+fn render(mut args: RenderArgs) -> Result<(), Box<dyn Error>> {
+    // A render that may be resumed needs a seed before the scene is built, so that the
+    // resumed run builds the same scene; an existing checkpoint supplies its own
+    if let (None, Some(path)) = (args.seed, &args.checkpoint) {
+        args.seed = Some(checkpoint_seed(path)?.unwrap_or_else(rand::random));
+    }
+    if let Some(seed) = args.seed {
+        seed_random(seed);
+    }
+    let scene = scene::load(&args.scene)?;
+
+    let threads = args
+        .threads
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(8, |n| n.get() as u32))
+        .max(1);
+    let format = match args.format {
+        Some(format) => format,
+        None => Format::from_path(&args.output).ok_or_else(|| {
+            format!(
+                "cannot tell the image format of {}, pass --format",
+                args.output.display()
+            )
+        })?,
+    };
+
+    let frames = match (&scene.animation, args.frames) {
+        (None, Some(_)) => return Err("--frames needs a scene with an animation".into()),
+        (None, None) => None,
+        (Some(animation), frames) => {
+            Some(frames.unwrap_or((animation.first_frame, animation.last_frame)))
+        }
+    };
+    match frames {
+        None => render_frame(scene, &args, None, format, threads),
+        Some((first, last)) => {
+            for frame in first..=last {
+                eprintln!("frame {} of {}-{}", frame, first, last);
+                render_frame(scene.frame(frame), &args, Some(frame), format, threads)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+// Renders one image of the scene; every output path gets the frame number when
+// there is one.
+fn render_frame(
+    scene: Scene,
+    args: &RenderArgs,
+    frame: Option<u32>,
+    format: Format,
+    threads: u32,
+) -> Result<(), Box<dyn Error>> {
+    let path_of = |path: &Path| match frame {
+        Some(frame) => frame_path(path, frame),
+        None => path.to_path_buf(),
+    };
+    let output = path_of(&args.output);
+    let output = output.as_path();
+    let mut cam = scene.camera;
+    if let Some(width) = args.width {
+        cam.width = width;
+    }
+    if let Some(height) = args.height {
+        // Kept exact rather than going through aspect_ratio, which rounds it down
+        cam.fixed_height = Some(height);
+        cam.aspect_ratio = cam.width as f64 / height as f64;
+    }
+    if let Some(spp) = args.spp {
+        cam.samples_per_pixel = spp;
+    }
+    if let Some(max_depth) = args.max_depth {
+        cam.max_depth = max_depth;
+    }
+    if let Some(rr_depth) = args.rr_depth {
+        cam.rr_depth = rr_depth;
+    }
+    if let Some(mis) = args.mis {
+        cam.mis = mis;
+    }
+    cam.seed = args.seed;
+    stats::set_enabled(args.stats || args.stats_json.is_some());
+    cam.crop = args.crop;
+    if let Some(crop) = cam.crop {
+        let height = cam.image_height();
+        if crop.x as u64 + crop.width as u64 > cam.width as u64
+            || crop.y as u64 + crop.height as u64 > height as u64
+        {
+            return Err(format!(
+                "--crop {},{},{},{} reaches outside the {}x{} image",
+                crop.x, crop.y, crop.width, crop.height, cam.width, height
+            )
+            .into());
+        }
+    }
+    cam.aovs = args.aovs || args.denoise.is_some();
+    if let Some(threshold) = args.adaptive {
+        cam.adaptive = Some(Adaptive {
+            min_spp: args.min_spp,
+            threshold,
+        });
+    }
+    if let Some(Err(message)) = cam.adaptive.map(|a| a.validate()) {
+        return Err(format!("--min-spp: {}", message).into());
+    }
+
+    let checkpoint_path = args.checkpoint.as_deref().map(path_of);
+    let mut checkpoint = match &checkpoint_path {
+        Some(path) => {
+            let settings = Settings {
+                scene: args.scene.display().to_string(),
+                scene_hash: checkpoint::hash_file(&args.scene)?,
+                frame,
+                width: cam.width,
+                aspect_ratio: cam.aspect_ratio,
+                samples_per_pixel: cam.samples_per_pixel,
+                max_depth: cam.max_depth,
+                rr_depth: cam.rr_depth,
+                mis: cam.mis,
+                adaptive: cam.adaptive.map(|a| (a.min_spp, a.threshold)),
+                crop: cam.crop.map(|c| [c.x, c.y, c.width, c.height]),
+                aovs: cam.aovs,
+            };
+            let checkpoint = Checkpoint::open(path, settings, cam.seed)?;
+            cam.seed = Some(checkpoint.seed());
+            Some(checkpoint)
+        }
+        None => None,
+    };
+    let mut result = cam.render(scene.world, scene.lights, threads, checkpoint.as_mut());
+    if args.full_frame && cam.crop.is_some() {
+        result = result.into_full_frame(cam.width, cam.height);
+    }
+    if let (Some(strength), Some(aovs)) = (args.denoise, &result.aovs) {
+        result.image = denoise::denoise(&result.image, aovs, strength);
+    }
+    if format.is_float() {
+        result.image = output::expose(&result.image, args.exposure);
+    } else {
+        result.image = output::tone_map(&result.image, args.tonemap, args.exposure, args.white);
+    }
+    match &result.aovs {
+        Some(aovs) if args.aovs => write_aovs(&result.image, aovs, output, format)?,
+        _ => write_output(&result.image, output, format)?,
+    }
+    if let Some(path) = &args.sample_heatmap {
+        let path = path_of(path);
+        let heatmap = output::sample_heatmap(
+            &result.sample_counts,
+            result.image.width,
+            result.image.height,
+        );
+        write_output(
+            &heatmap,
+            &path,
+            Format::from_path(&path).unwrap_or(Format::Png),
+        )?;
+    }
+    if args.stats {
+        result.stats.report(&mut std::io::stderr())?;
+    }
+    if let Some(path) = &args.stats_json {
+        let path = path_of(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, serde_json::to_string_pretty(&result.stats)?)?;
+    }
+    if let Some(path) = checkpoint_path {
+        drop(checkpoint);
+        fs::remove_file(path)?;
+    }
+    Ok(())
+}
+
+fn write_output(fb: &Framebuffer, path: &Path, format: Format) -> Result<(), Box<dyn Error>> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    output::write_image(fb, path, format)
+}
+
+fn write_aovs(
+    image: &Framebuffer,
+    aovs: &AovImages,
+    path: &Path,
+    format: Format,
+) -> Result<(), Box<dyn Error>> {
+    if format == Format::Exr {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut layers = vec![("", image, &["R", "G", "B"][..])];
+        layers.extend(aovs.layers());
+        return output::write_exr_layers(&layers, path);
+    }
+
+    write_output(image, path, format)?;
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let ext = path.extension().unwrap_or_default().to_string_lossy();
+    for (name, fb, _) in aovs.layers() {
+        let pass_path = path.with_file_name(format!("{}.{}.{}", stem, name, ext));
+        // Display formats get something viewable instead of raw values
+        let preview = match name {
+            _ if format.is_float() => fb.clone(),
+            "normal" => output::normal_preview(fb),
+            "depth" => output::depth_preview(fb),
+            "object_id" => output::id_preview(fb),
+            _ => fb.clone(),
+        };
+        write_output(&preview, &pass_path, format)?;
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+struct Counts {
+    spheres: usize,
+    quads: usize,
+    triangles: usize,
+    media: usize,
+    transforms: usize,
+    bvh_nodes: usize,
+    bvh_depth: usize,
+}
+
+fn count(object: &HitObject, depth: usize, counts: &mut Counts) {
+    match object {
+        HitObject::Sphere { .. } => counts.spheres += 1,
+        HitObject::Quad { .. } => counts.quads += 1,
+        HitObject::Triangle { .. } => counts.triangles += 1,
+        HitObject::Bvh { left, right, .. } => {
+            counts.bvh_nodes += 1;
+            counts.bvh_depth = counts.bvh_depth.max(depth + 1);
+            count(left, depth + 1, counts);
+            count(right, depth + 1, counts);
+        }
+        HitObject::HittableList { objects, .. } => {
+            for object in objects {
+                count(object, depth, counts);
+            }
+        }
+        HitObject::Translate { object, .. } | HitObject::Rotate { object, .. } => {
+            counts.transforms += 1;
+            count(object, depth, counts);
+        }
+        HitObject::ConstantMedium { boundary, .. } => {
+            counts.media += 1;
+            count(boundary, depth, counts);
+        }
+        HitObject::Tagged { object, .. } => count(object, depth, counts),
+    }
+}
+
+fn info(path: &Path) -> Result<(), Box<dyn Error>> {
+    let scene = scene::load(path)?;
+    let cam = scene.camera;
+    let height = cam.image_height();
+
+    let mut counts = Counts::default();
+    count(&scene.world, 0, &mut counts);
+    let bbox = scene.world.bounding_box();
+
+    println!("scene:      {}", path.display());
+    println!("resolution: {}x{}", cam.width, height);
+    println!("samples:    {} per pixel", cam.samples_per_pixel);
+    println!(
+        "max depth:  {}, roulette after {}",
+        cam.max_depth, cam.rr_depth
+    );
+    if let Some(animation) = &scene.animation {
+        println!(
+            "frames:     {}-{}{}",
+            animation.first_frame,
+            animation.last_frame,
+            if animation.static_world() {
+                ", camera only"
+            } else {
+                ""
+            }
+        );
+    }
+    println!("spheres:    {}", counts.spheres);
+    println!("quads:      {}", counts.quads);
+    println!("triangles:  {}", counts.triangles);
+    println!("media:      {}", counts.media);
+    println!("transforms: {}", counts.transforms);
+    println!(
+        "lights:     {} sampled, {} delta",
+        scene.lights.len(),
+        cam.delta_lights.len()
+    );
+    println!(
+        "bvh:        {} nodes, depth {}",
+        counts.bvh_nodes, counts.bvh_depth
+    );
+    println!(
+        "bounds:     [{:.3}, {:.3}, {:.3}] - [{:.3}, {:.3}, {:.3}]",
+        bbox.b[0].min, bbox.b[1].min, bbox.b[2].min, bbox.b[0].max, bbox.b[1].max, bbox.b[2].max
+    );
+    Ok(())
+}
+
+fn compare(args: &CompareArgs) -> Result<(), Box<dyn Error>> {
+    let a = load_hdr_image(&args.first)?;
+    let b = load_hdr_image(&args.second)?;
+    if (a.width, a.height) != (b.width, b.height) {
+        return Err(format!(
+            "image sizes differ: {:?} vs {:?}",
+            (a.width, a.height),
+            (b.width, b.height)
+        )
+        .into());
+    }
+
+    // On linear values, so float outputs aren't clamped or rounded to 8 bits first
+    let mut sum_sq = 0.0;
+    let mut max_diff: f64 = 0.0;
+    for y in 0..a.height {
+        for x in 0..a.width {
+            let (pa, pb) = (a.pixel_data(x, y), b.pixel_data(x, y));
+            for c in 0..3 {
+                let d = (pa[c] - pb[c]).abs();
+                sum_sq += d * d;
+                max_diff = max_diff.max(d);
+            }
+        }
+    }
+    let rmse = (sum_sq / (a.width * a.height * 3) as f64).sqrt();
+    let psnr = if rmse > 0.0 {
+        20.0 * (1.0 / rmse).log10()
+    } else {
+        f64::INFINITY
+    };
+    println!("rmse:     {:.6}", rmse);
+    println!("psnr:     {:.2} dB", psnr);
+    println!("max diff: {:.6}", max_diff);
+
+    match args.threshold {
+        Some(threshold) if rmse > threshold => {
+            Err(format!("rmse {:.6} is above threshold {}", rmse, threshold).into())
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,106 @@
+use crate::rtweekend::vec3::{Color, Point3, Vec3};
+use crate::rtweekend::INF;
+
+// Lights with no surface: a point, a point shining into a cone, or parallel light from
+// infinitely far away. Rays can never run into them, so every bounce that isn't
+// specular traces a shadow ray to each one instead.
+#[derive(Clone, Debug)]
+pub enum DeltaLight {
+    Point {
+        position: Point3,
+        intensity: Color, // Radiant intensity, falling off with the squared distance
+    },
+    Spot {
+        position: Point3,
+        direction: Vec3, // Unit axis of the cone, pointing away from the light
+        intensity: Color,
+        cos_cone: f64,    // Nothing outside this angle from the axis
+        cos_falloff: f64, // Full intensity inside this one
+    },
+    Directional {
+        direction: Vec3,   // Unit direction the light travels in
+        irradiance: Color, // On a surface facing the light
+    },
+}
+
+pub fn build_point_light(position: Point3, intensity: Color) -> DeltaLight {
+    DeltaLight::Point {
+        position,
+        intensity,
+    }
+}
+// cone_angle is the half-angle of the cone in degrees; falloff is how many degrees at
+// its rim the intensity takes to fade out.
+pub fn build_spot_light(
+    position: Point3,
+    direction: Vec3,
+    intensity: Color,
+    cone_angle: f64,
+    falloff: f64,
+) -> DeltaLight {
+    let inner = (cone_angle - falloff).max(0.0);
+    DeltaLight::Spot {
+        position,
+        direction: Vec3::unit_vector(direction),
+        intensity,
+        cos_cone: cone_angle.to_radians().cos(),
+        cos_falloff: inner.to_radians().cos(),
+    }
+}
+pub fn build_directional_light(direction: Vec3, irradiance: Color) -> DeltaLight {
+    DeltaLight::Directional {
+        direction: Vec3::unit_vector(direction),
+        irradiance,
+    }
+}
+
+impl DeltaLight {
+    // Unit direction from p to the light, how far away it is, and the light arriving at
+    // p from it, before any shadowing.
+    pub fn sample(&self, p: &Point3) -> (Vec3, f64, Color) {
+        match self {
+            DeltaLight::Point {
+                position,
+                intensity,
+            } => {
+                let to_light = *position - *p;
+                let distance_squared = to_light.sq_length();
+                (
+                    Vec3::unit_vector(to_light),
+                    distance_squared.sqrt(),
+                    *intensity / distance_squared,
+                )
+            }
+            DeltaLight::Spot {
+                position,
+                direction,
+                intensity,
+                cos_cone,
+                cos_falloff,
+            } => {
+                let to_light = *position - *p;
+                let distance_squared = to_light.sq_length();
+                let dir = Vec3::unit_vector(to_light);
+                let cos_theta = Vec3::dot(&-dir, direction);
+                // Smoothstep from the rim of the cone to where the falloff starts
+                let edge = if cos_falloff > cos_cone {
+                    ((cos_theta - cos_cone) / (cos_falloff - cos_cone)).clamp(0.0, 1.0)
+                } else if cos_theta >= *cos_cone {
+                    1.0
+                } else {
+                    0.0
+                };
+                let falloff = edge * edge * (3.0 - 2.0 * edge);
+                (
+                    dir,
+                    distance_squared.sqrt(),
+                    *intensity * (falloff / distance_squared),
+                )
+            }
+            DeltaLight::Directional {
+                direction,
+                irradiance,
+            } => (-*direction, INF, *irradiance),
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use crate::camera::AovImages;
+use crate::output::Framebuffer;
+use crate::rtweekend::color::luminance;
+use crate::rtweekend::vec3::Color;
+use crate::rtweekend::vec3::Vec3;
+
+// Edge-avoiding a-trous wavelet filter (Dammertz et al. 2010). Each pass blurs with a
+// 5x5 B3-spline kernel whose taps are spread 1, 2, 4, ... pixels apart, and every tap
+// is weighted down when its colour, normal, depth or object differs from the centre
+// pixel. Lighting is filtered with the albedo divided out, so textures stay sharp.
+
+const PASSES: u32 = 5;
+const KERNEL: [f64; 5] = [1.0 / 16.0, 1.0 / 4.0, 3.0 / 8.0, 1.0 / 4.0, 1.0 / 16.0];
+
+const SIGMA_COLOR: f64 = 0.5; // In gamma space, scaled by the strength
+const NORMAL_POWER: f64 = 64.0;
+const SIGMA_DEPTH: f64 = 0.02; // Relative to the centre pixel's depth, per tap step
+const ALBEDO_EPSILON: f64 = 0.01;
+
+// Filters image using the passes gathered with it. Strength scales how different two
+// colours may be and still get averaged; 0 leaves the image untouched.
+pub fn denoise(image: &Framebuffer, aovs: &AovImages, strength: f64) -> Framebuffer {
+    if strength <= 0.0 {
+        return image.clone();
+    }
+    let albedo = |k: usize| {
+        let a = aovs.albedo.pixels[k];
+        Color {
+            e: [
+                a.e[0].max(ALBEDO_EPSILON),
+                a.e[1].max(ALBEDO_EPSILON),
+                a.e[2].max(ALBEDO_EPSILON),
+            ],
+        }
+    };
+
+    let mut lighting = image.clone();
+    for (k, p) in lighting.pixels.iter_mut().enumerate() {
+        let a = albedo(k);
+        *p = Color {
+            e: [p.e[0] / a.e[0], p.e[1] / a.e[1], p.e[2] / a.e[2]],
+        };
+    }
+
+    lighting = clamp_fireflies(&lighting, aovs);
+
+    let mut sigma_color = SIGMA_COLOR * strength;
+    for pass in 0..PASSES {
+        lighting = filter_pass(&lighting, aovs, 1 << pass, sigma_color);
+        // Later passes average over larger areas, so they get stricter about colour
+        sigma_color *= 0.5;
+    }
+
+    for (k, p) in lighting.pixels.iter_mut().enumerate() {
+        *p = *p * albedo(k);
+    }
+    lighting
+}
+
+// A lone sample that found the light is far brighter than anything around it and the
+// colour test would keep it as an edge, so first limit each pixel's luminance to the
+// second brightest of its eight neighbours on the same object, which also catches
+// fireflies that come in pairs.
+fn clamp_fireflies(src: &Framebuffer, aovs: &AovImages) -> Framebuffer {
+    let (width, height) = (src.width as i64, src.height as i64);
+    let mut dst = src.clone();
+    for y in 0..height {
+        for x in 0..width {
+            let p = (y * width + x) as usize;
+            let (mut max, mut second) = (-1.0, -1.0);
+            for qy in (y - 1).max(0)..(y + 2).min(height) {
+                for qx in (x - 1).max(0)..(x + 2).min(width) {
+                    let q = (qy * width + qx) as usize;
+                    if q != p && aovs.object_id.pixels[q].e[0] == aovs.object_id.pixels[p].e[0] {
+                        let y_q = luminance(&src.pixels[q]);
+                        if y_q > max {
+                            second = max;
+                            max = y_q;
+                        } else if y_q > second {
+                            second = y_q;
+                        }
+                    }
+                }
+            }
+            let y_p = luminance(&src.pixels[p]);
+            if second >= 0.0 && y_p > second {
+                dst.pixels[p] = src.pixels[p] * (second / y_p);
+            }
+        }
+    }
+    dst
+}
+
+// Colour used for the edge-stopping test: gamma compressed so that bright emitters
+// do not dominate every difference.
+fn compressed(c: &Color) -> Color {
+    let f = |x: f64| if x > 0.0 { x.sqrt() } else { 0.0 };
+    Color {
+        e: [f(c.e[0]), f(c.e[1]), f(c.e[2])],
+    }
+}
+
+fn filter_pass(src: &Framebuffer, aovs: &AovImages, step: i64, sigma_color: f64) -> Framebuffer {
+    let (width, height) = (src.width as i64, src.height as i64);
+    let compressed: Vec<Color> = src.pixels.iter().map(compressed).collect();
+    let mut dst = Framebuffer::new(src.width, src.height);
+
+    for y in 0..height {
+        for x in 0..width {
+            let p = (y * width + x) as usize;
+            let id_p = aovs.object_id.pixels[p].e[0];
+            let normal_p = Vec3::unit_vector(aovs.normal.pixels[p]);
+            let depth_p = aovs.depth.pixels[p].e[0];
+
+            let mut sum = Color::new();
+            let mut weight_sum = 0.0;
+            for (j, ky) in KERNEL.iter().enumerate() {
+                for (i, kx) in KERNEL.iter().enumerate() {
+                    let qx = x + (i as i64 - 2) * step;
+                    let qy = y + (j as i64 - 2) * step;
+                    if qx < 0 || qy < 0 || qx >= width || qy >= height {
+                        continue;
+                    }
+                    let q = (qy * width + qx) as usize;
+
+                    // Different objects, or a hit next to a miss, never blend
+                    if aovs.object_id.pixels[q].e[0] != id_p {
+                        continue;
+                    }
+                    let diff = compressed[p] - compressed[q];
+                    let mut w = kx * ky * (-diff.sq_length() / (sigma_color * sigma_color)).exp();
+                    if id_p != 0.0 {
+                        let normal_q = Vec3::unit_vector(aovs.normal.pixels[q]);
+                        let cos = Vec3::dot(&normal_p, &normal_q).max(0.0);
+                        w *= cos.powf(NORMAL_POWER);
+                        let dz = (depth_p - aovs.depth.pixels[q].e[0]).abs();
+                        w *= (-dz / (SIGMA_DEPTH * depth_p * step as f64 + 1e-8)).exp();
+                    }
+                    if w.is_nan() || w <= 0.0 || !luminance(&src.pixels[q]).is_finite() {
+                        continue;
+                    }
+                    sum = sum + src.pixels[q] * w;
+                    weight_sum += w;
+                }
+            }
+            let c = if weight_sum > 0.0 {
+                sum / weight_sum
+            } else {
+                src.pixels[p]
+            };
+            dst.set(x as u32, y as u32, c);
+        }
+    }
+    dst
+}
@@ -0,0 +1,285 @@
+pub mod sky;
+
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use crate::hittable_list::hittable::random_to_sphere;
+use crate::onb::Onb;
+use crate::rtw_image::RtwImage;
+use crate::rtweekend::color::luminance;
+use crate::rtweekend::distribution::Distribution2D;
+use crate::rtweekend::random_double_01;
+use crate::rtweekend::vec3::{Color, Vec3};
+use sky::{sun_radiance, Preetham};
+
+// Resolution of the table the sky is importance sampled from.
+const SKY_TABLE_WIDTH: u32 = 128;
+const SKY_TABLE_HEIGHT: u32 = 64;
+
+// Light arriving from infinitely far away, seen by every ray that leaves the scene.
+#[derive(Clone, Debug)]
+pub enum Environment {
+    Solid {
+        color: Color,
+    },
+    // Equirectangular image laid out like the equirectangular camera renders: longitude
+    // across the width with -z in the middle and +x right of it, +y along the top row.
+    // Turned about +y like rotate_y, and importance sampled by luminance. The image and
+    // table are shared by the copies of the camera each render thread gets.
+    Map {
+        image: Arc<RtwImage>,
+        sin_theta: f64,
+        cos_theta: f64,
+        intensity: f64,
+        distribution: Arc<Distribution2D>,
+    },
+    // Analytic daylight with a sun disk, over a ground that reflects it diffusely.
+    // The sun is sampled as a cone, the rest from a table laid out like a map.
+    Sky {
+        sky: Box<Preetham>,
+        sun_direction: Vec3,
+        cos_sun: f64, // Cosine of the sun's angular radius
+        sun_radiance: Color,
+        ground: Color,
+        intensity: f64,
+        sun_probability: f64,
+        distribution: Arc<Distribution2D>,
+    },
+}
+
+pub fn build_environment_map(image: RtwImage, rotation: f64, intensity: f64) -> Environment {
+    let (width, height) = (image.width, image.height);
+    let mut weights = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        // Rows near the poles cover less of the sphere
+        let latitude = (0.5 - (y as f64 + 0.5) / height as f64) * PI;
+        for x in 0..width {
+            let e = image.pixel_data(x, y);
+            weights.push(luminance(&Color { e }) * latitude.cos());
+        }
+    }
+    let radians = rotation.to_radians();
+    Environment::Map {
+        distribution: Arc::new(Distribution2D::new(
+            &weights,
+            width as usize,
+            height as usize,
+        )),
+        image: Arc::new(image),
+        sin_theta: radians.sin(),
+        cos_theta: radians.cos(),
+        intensity,
+    }
+}
+
+// sun_size is the angular diameter of the sun disk in degrees.
+pub fn build_sky(
+    sun_direction: Vec3,
+    turbidity: f64,
+    ground_albedo: f64,
+    sun_size: f64,
+    intensity: f64,
+) -> Environment {
+    let sun_direction = Vec3::unit_vector(sun_direction);
+    let sky = Preetham::new(sun_direction, turbidity);
+    let cos_sun = (sun_size.to_radians() / 2.0).cos();
+    let sun_solid_angle = 2.0 * PI * (1.0 - cos_sun);
+    let sun = sun_radiance(&sun_direction, turbidity, sun_solid_angle);
+
+    // The ground is lit by the sky and the sun on a horizontal plane
+    let (width, height) = (SKY_TABLE_WIDTH, SKY_TABLE_HEIGHT);
+    let cell = 2.0 * PI * PI / (width * height) as f64;
+    let mut sky_radiance = Vec::with_capacity((width * height) as usize);
+    let mut irradiance = sun * (sun_solid_angle * sun_direction.e[1]);
+    for y in 0..height {
+        for x in 0..width {
+            let (u, v) = (
+                (x as f64 + 0.5) / width as f64,
+                (y as f64 + 0.5) / height as f64,
+            );
+            let dir = map_direction(u, v, 0.0, 1.0);
+            let latitude = dir.e[1].asin();
+            let radiance = sky.radiance(&dir);
+            if latitude > 0.0 {
+                irradiance = irradiance + radiance * (latitude.sin() * latitude.cos() * cell);
+            }
+            sky_radiance.push((radiance, latitude));
+        }
+    }
+    let ground = irradiance * (ground_albedo / PI);
+
+    let mut sky_power = 0.0;
+    let weights: Vec<f64> = sky_radiance
+        .iter()
+        .map(|(radiance, latitude)| {
+            let radiance = if *latitude > 0.0 { radiance } else { &ground };
+            let w = luminance(radiance) * latitude.cos();
+            sky_power += w * cell;
+            w
+        })
+        .collect();
+    let sun_power = luminance(&sun) * sun_solid_angle;
+
+    Environment::Sky {
+        sky: Box::new(sky),
+        sun_direction,
+        cos_sun,
+        sun_radiance: sun,
+        ground,
+        intensity,
+        // Neither part is left unsampled, whatever their balance
+        sun_probability: (sun_power / (sun_power + sky_power)).clamp(0.1, 0.9),
+        distribution: Arc::new(Distribution2D::new(
+            &weights,
+            width as usize,
+            height as usize,
+        )),
+    }
+}
+
+impl Environment {
+    pub fn radiance(&self, dir: &Vec3) -> Color {
+        match self {
+            Environment::Solid { color } => *color,
+            Environment::Map {
+                image,
+                sin_theta,
+                cos_theta,
+                intensity,
+                distribution: _,
+            } => {
+                let (u, v, _) = map_coords(dir, *sin_theta, *cos_theta);
+                let x = ((u * image.width as f64) as u32).min(image.width - 1);
+                let y = ((v * image.height as f64) as u32).min(image.height - 1);
+                Color {
+                    e: image.pixel_data(x, y),
+                } * *intensity
+            }
+            Environment::Sky {
+                sky,
+                sun_direction,
+                cos_sun,
+                sun_radiance,
+                ground,
+                intensity,
+                sun_probability: _,
+                distribution: _,
+            } => {
+                let d = Vec3::unit_vector(*dir);
+                if d.e[1] < 0.0 {
+                    return *ground * *intensity;
+                }
+                let mut radiance = sky.radiance(&d);
+                if Vec3::dot(&d, sun_direction) >= *cos_sun {
+                    radiance = radiance + *sun_radiance;
+                }
+                radiance * *intensity
+            }
+        }
+    }
+    // Whether directions towards it are worth sampling; a solid colour lights every
+    // direction alike, which the material's own pdf already handles well.
+    pub fn is_sampled(&self) -> bool {
+        !matches!(self, Environment::Solid { .. })
+    }
+    pub fn pdf_value(&self, dir: &Vec3) -> f64 {
+        match self {
+            Environment::Solid { color: _ } => 1.0 / (4.0 * PI),
+            Environment::Map {
+                image: _,
+                sin_theta,
+                cos_theta,
+                intensity: _,
+                distribution,
+            } => map_pdf(distribution, dir, *sin_theta, *cos_theta),
+            Environment::Sky {
+                sky: _,
+                sun_direction,
+                cos_sun,
+                sun_radiance: _,
+                ground: _,
+                intensity: _,
+                sun_probability,
+                distribution,
+            } => {
+                let mut pdf = (1.0 - sun_probability) * map_pdf(distribution, dir, 0.0, 1.0);
+                if Vec3::dot(&Vec3::unit_vector(*dir), sun_direction) >= *cos_sun {
+                    pdf += sun_probability / (2.0 * PI * (1.0 - cos_sun));
+                }
+                pdf
+            }
+        }
+    }
+    pub fn random_direction(&self) -> Vec3 {
+        match self {
+            Environment::Solid { color: _ } => Vec3::random_unit_vector(),
+            Environment::Map {
+                image: _,
+                sin_theta,
+                cos_theta,
+                intensity: _,
+                distribution,
+            } => {
+                let (u, v) = distribution.sample(random_double_01(), random_double_01());
+                map_direction(u, v, *sin_theta, *cos_theta)
+            }
+            Environment::Sky {
+                sky: _,
+                sun_direction,
+                cos_sun,
+                sun_radiance: _,
+                ground: _,
+                intensity: _,
+                sun_probability,
+                distribution,
+            } => {
+                if random_double_01() < *sun_probability {
+                    let sin_sun = (1.0 - cos_sun * cos_sun).sqrt();
+                    Onb::build_from_w(*sun_direction).local(&random_to_sphere(sin_sun, 1.0))
+                } else {
+                    let (u, v) = distribution.sample(random_double_01(), random_double_01());
+                    map_direction(u, v, 0.0, 1.0)
+                }
+            }
+        }
+    }
+}
+
+// World direction at image coordinates (u, v) of a map turned by theta about +y.
+fn map_direction(u: f64, v: f64, sin_theta: f64, cos_theta: f64) -> Vec3 {
+    let longitude = (u - 0.5) * 2.0 * PI;
+    let latitude = (0.5 - v) * PI;
+    let x = latitude.cos() * longitude.sin();
+    let z = -latitude.cos() * longitude.cos();
+    Vec3 {
+        e: [
+            cos_theta * x + sin_theta * z,
+            latitude.sin(),
+            -sin_theta * x + cos_theta * z,
+        ],
+    }
+}
+
+// Solid angle density of directions sampled from distribution over a map.
+fn map_pdf(distribution: &Distribution2D, dir: &Vec3, sin_theta: f64, cos_theta: f64) -> f64 {
+    let (u, v, cos_latitude) = map_coords(dir, sin_theta, cos_theta);
+    if cos_latitude <= 0.0 {
+        return 0.0;
+    }
+    // The map spans 2pi by pi radians, squeezed by cos(latitude) on the sphere
+    distribution.pdf(u, v) / (2.0 * PI * PI * cos_latitude)
+}
+
+// Image coordinates in [0, 1)^2 of a world direction, and the cosine of its latitude.
+fn map_coords(dir: &Vec3, sin_theta: f64, cos_theta: f64) -> (f64, f64, f64) {
+    let d = Vec3::unit_vector(*dir);
+    let x = cos_theta * d.e[0] - sin_theta * d.e[2];
+    let z = sin_theta * d.e[0] + cos_theta * d.e[2];
+    let latitude = d.e[1].clamp(-1.0, 1.0).asin();
+    let longitude = x.atan2(-z);
+    (
+        longitude / (2.0 * PI) + 0.5,
+        0.5 - latitude / PI,
+        latitude.cos(),
+    )
+}
@@ -0,0 +1,116 @@
+use std::f64::consts::PI;
+
+use crate::rtweekend::vec3::{Color, Vec3};
+
+// Sky and sun come out in kcd/m^2 and are scaled by this, so that a white diffuse
+// surface under a high sun ends up near 1.
+const SKY_SCALE: f64 = 0.03;
+// Illuminance of the sun above the atmosphere, in klux.
+const SUN_ILLUMINANCE: f64 = 133.3;
+
+// Clear sky from Preetham, Shirley and Smits, "A Practical Analytic Model for Daylight"
+// (1999). Luminance and chromaticity each follow a Perez distribution around the sun,
+// scaled to the zenith value fitted for the sun height and turbidity.
+#[derive(Clone, Debug)]
+pub struct Preetham {
+    sun: Vec3,
+    perez: [[f64; 5]; 3], // A to E for Y, x and y
+    zenith: [f64; 3],     // Y, x and y at the zenith, divided by F(0, sun angle)
+}
+impl Preetham {
+    // sun is the unit direction towards the sun, which has to be above the horizon.
+    pub fn new(sun: Vec3, turbidity: f64) -> Preetham {
+        let t = turbidity;
+        let perez = [
+            [
+                0.1787 * t - 1.4630,
+                -0.3554 * t + 0.4275,
+                -0.0227 * t + 5.3251,
+                0.1206 * t - 2.5771,
+                -0.0670 * t + 0.3703,
+            ],
+            [
+                -0.0193 * t - 0.2592,
+                -0.0665 * t + 0.0008,
+                -0.0004 * t + 0.2125,
+                -0.0641 * t - 0.8989,
+                -0.0033 * t + 0.0452,
+            ],
+            [
+                -0.0167 * t - 0.2608,
+                -0.0950 * t + 0.0092,
+                -0.0079 * t + 0.2102,
+                -0.0441 * t - 1.6537,
+                -0.0109 * t + 0.0529,
+            ],
+        ];
+
+        let theta = sun.e[1].clamp(0.0, 1.0).acos();
+        let (theta2, theta3) = (theta * theta, theta * theta * theta);
+        let chi = (4.0 / 9.0 - t / 120.0) * (PI - 2.0 * theta);
+        let zenith_y = (4.0453 * t - 4.9710) * chi.tan() - 0.2155 * t + 2.4192;
+        let zenith_x = t * t * (0.00166 * theta3 - 0.00375 * theta2 + 0.00209 * theta)
+            + t * (-0.02903 * theta3 + 0.06377 * theta2 - 0.03202 * theta + 0.00394)
+            + (0.11693 * theta3 - 0.21196 * theta2 + 0.06052 * theta + 0.25886);
+        let zenith_yc = t * t * (0.00275 * theta3 - 0.00610 * theta2 + 0.00317 * theta)
+            + t * (-0.04214 * theta3 + 0.08970 * theta2 - 0.04153 * theta + 0.00516)
+            + (0.15346 * theta3 - 0.26756 * theta2 + 0.06670 * theta + 0.26688);
+
+        let mut zenith = [zenith_y, zenith_x, zenith_yc];
+        for (z, c) in zenith.iter_mut().zip(&perez) {
+            *z /= perez_f(c, 1.0, theta.cos());
+        }
+        Preetham { sun, perez, zenith }
+    }
+    // Radiance of the sky in direction dir, without the sun disk. Directions below
+    // the horizon get the sky just above it.
+    pub fn radiance(&self, dir: &Vec3) -> Color {
+        let d = Vec3::unit_vector(*dir);
+        let cos_theta = d.e[1].max(0.001);
+        let cos_gamma = Vec3::dot(&d, &self.sun).clamp(-1.0, 1.0);
+        let [y, x, yc] =
+            [0, 1, 2].map(|k| self.zenith[k] * perez_f(&self.perez[k], cos_theta, cos_gamma));
+        xyy_to_rgb(x, yc, y.max(0.0)) * SKY_SCALE
+    }
+}
+
+// Perez et al. sky distribution for view zenith angle theta and angle gamma to the sun.
+fn perez_f(c: &[f64; 5], cos_theta: f64, cos_gamma: f64) -> f64 {
+    let gamma = cos_gamma.acos();
+    (1.0 + c[0] * (c[1] / cos_theta).exp())
+        * (1.0 + c[2] * (c[3] * gamma).exp() + c[4] * cos_gamma * cos_gamma)
+}
+
+// CIE xyY to linear sRGB.
+fn xyy_to_rgb(x: f64, y: f64, luminance: f64) -> Color {
+    if y <= 0.0 {
+        return Color::new();
+    }
+    let big_x = x / y * luminance;
+    let big_z = (1.0 - x - y) / y * luminance;
+    let big_y = luminance;
+    Color {
+        e: [
+            (3.2406 * big_x - 1.5372 * big_y - 0.4986 * big_z).max(0.0),
+            (-0.9689 * big_x + 1.8758 * big_y + 0.0415 * big_z).max(0.0),
+            (0.0557 * big_x - 0.2040 * big_y + 1.0570 * big_z).max(0.0),
+        ],
+    }
+}
+
+// Radiance of a sun disk of the given solid angle seen through the atmosphere: sunlight
+// loses blue to Rayleigh scattering and some of everything to haze along the air mass,
+// which is what reddens a low sun. Wavelengths in micrometres stand in for R, G and B.
+pub fn sun_radiance(sun: &Vec3, turbidity: f64, solid_angle: f64) -> Color {
+    let elevation = sun.e[1].clamp(0.0, 1.0).asin().to_degrees();
+    // Kasten and Young's relative air mass
+    let air_mass =
+        1.0 / (elevation.to_radians().sin() + 0.50572 * (elevation + 6.07995).powf(-1.6364));
+    let beta = 0.04608 * turbidity - 0.04586;
+    let transmittance = [0.680, 0.550, 0.440].map(|lambda: f64| {
+        let rayleigh = 0.008735 * lambda.powf(-4.08);
+        let aerosol = beta * lambda.powf(-1.3);
+        (-(rayleigh + aerosol) * air_mass).exp()
+    });
+    Color { e: transmittance } * (SUN_ILLUMINANCE / solid_angle * SKY_SCALE)
+}
@@ -9,12 +9,14 @@ use crate::aabb::merge;
 use crate::aabb::Aabb;
 
 //use crate::aabb::point_to_aabb;
+use crate::rtweekend::color::luminance;
 use crate::rtweekend::interval::Interval;
 use crate::rtweekend::random_double_01;
 use crate::rtweekend::random_int;
 use crate::rtweekend::ray::Ray;
 use crate::rtweekend::vec3::Point3;
 use crate::rtweekend::INF;
+use crate::stats;
 //use crate::rtweekend::vec3::Color;
 use crate::rtweekend::vec3::Vec3;
 use hittable::HitRecord;
@@ -28,7 +30,9 @@ pub enum HitObject {
         radius: f64,
         mat: Material,
         is_moving: bool,
-        center_vec: Vec3,
+        center_vec: Vec3, // Displacement from time0 to time1
+        time0: f64,       // The sphere rests at center_st until time0
+        time1: f64,       // and at center_st + center_vec after time1
         bbox: Aabb,
     },
     Quad {
@@ -46,6 +50,7 @@ pub enum HitObject {
         left: Box<HitObject>,
         right: Box<HitObject>,
         bbox: Aabb,
+        area: f64, // Of everything below that lights can be sampled on
     },
     HittableList {
         objects: Vec<HitObject>,
@@ -68,14 +73,19 @@ pub enum HitObject {
         phase_function: Material,
     },
     Triangle {
-        v1:Point3,
-        v2:Point3,
-        v3:Point3,
-        mat:Material,
-        normal:Vec3,
-        bbox:Aabb,
-        area:f64,
-    }
+        v1: Point3,
+        v2: Point3,
+        v3: Point3,
+        mat: Material,
+        normal: Vec3,
+        bbox: Aabb,
+        area: f64,
+    },
+    // Marks everything below it as one scene object, for the object id pass
+    Tagged {
+        object: Box<HitObject>,
+        id: u32,
+    },
 }
 
 impl HitObject {
@@ -87,6 +97,8 @@ impl HitObject {
                 mat: _,
                 is_moving: _,
                 center_vec: _,
+                time0: _,
+                time1: _,
                 bbox,
             } => bbox.clone(),
             HitObject::Quad {
@@ -104,6 +116,7 @@ impl HitObject {
                 left: _,
                 right: _,
                 bbox,
+                area: _,
             } => bbox.clone(),
             HitObject::HittableList { objects: _, bbox } => bbox.clone(),
             HitObject::Translate {
@@ -123,14 +136,15 @@ impl HitObject {
                 phase_function: _,
             } => boundary.bounding_box(),
             HitObject::Triangle {
-                v1:_,
-                v2:_,
-                v3:_,
-                normal:_,
-                mat:_,
+                v1: _,
+                v2: _,
+                v3: _,
+                normal: _,
+                mat: _,
                 bbox,
-                area:_,
-            }=>bbox.clone(),
+                area: _,
+            } => bbox.clone(),
+            HitObject::Tagged { object, id: _ } => object.bounding_box(),
         }
     }
     pub fn get_objects(&self) -> Vec<HitObject> {
@@ -141,6 +155,8 @@ impl HitObject {
                 mat: _,
                 is_moving: _,
                 center_vec: _,
+                time0: _,
+                time1: _,
                 bbox: _,
             } => Vec::new(),
             HitObject::Quad {
@@ -158,6 +174,7 @@ impl HitObject {
                 left: _,
                 right: _,
                 bbox: _,
+                area: _,
             } => Vec::new(),
             HitObject::HittableList { objects, bbox: _ } => objects.clone(),
             HitObject::Translate {
@@ -177,14 +194,15 @@ impl HitObject {
                 phase_function: _,
             } => Vec::new(),
             HitObject::Triangle {
-                v1:_,
-                v2:_,
-                v3:_,
-                normal:_,
-                mat:_,
-                bbox:_,
-                area:_,
-            }=>Vec::new()
+                v1: _,
+                v2: _,
+                v3: _,
+                normal: _,
+                mat: _,
+                bbox: _,
+                area: _,
+            } => Vec::new(),
+            HitObject::Tagged { object: _, id: _ } => Vec::new(),
         }
     }
     fn cur_center(&self, time: f64) -> Point3 {
@@ -195,8 +213,17 @@ impl HitObject {
                 mat: _,
                 is_moving: _,
                 center_vec,
+                time0,
+                time1,
                 bbox: _,
-            } => *center_st + (*center_vec) * time,
+            } => {
+                let t = if time1 > time0 {
+                    ((time - time0) / (time1 - time0)).clamp(0.0, 1.0)
+                } else {
+                    1.0
+                };
+                *center_st + (*center_vec) * t
+            }
             HitObject::Quad {
                 q: _,
                 u: _,
@@ -212,6 +239,7 @@ impl HitObject {
                 left: _,
                 right: _,
                 bbox: _,
+                area: _,
             } => Vec3::new(),
             HitObject::HittableList {
                 objects: _,
@@ -234,14 +262,15 @@ impl HitObject {
                 phase_function: _,
             } => Vec3::new(),
             HitObject::Triangle {
-                v1:_,
-                v2:_,
-                v3:_,
-                normal:_,
-                mat:_,
-                bbox:_,
-                area:_,
+                v1: _,
+                v2: _,
+                v3: _,
+                normal: _,
+                mat: _,
+                bbox: _,
+                area: _,
             } => Vec3::new(),
+            HitObject::Tagged { object: _, id: _ } => Vec3::new(),
         }
     }
     pub fn hit(&self, r: &Ray, ray_t: &Interval) -> (HitRecord, bool) {
@@ -252,8 +281,11 @@ impl HitObject {
                 mat,
                 is_moving,
                 center_vec: _,
+                time0: _,
+                time1: _,
                 bbox: _,
             } => {
+                stats::count(|s| s.primitive_tests.sphere += 1);
                 let mut rec = HitRecord::new();
                 let center = if *is_moving {
                     self.cur_center(r.tm)
@@ -295,6 +327,7 @@ impl HitObject {
                 d,
                 area: _,
             } => {
+                stats::count(|s| s.primitive_tests.quad += 1);
                 let mut rec = HitRecord::new();
                 let denom = Vec3::dot(normal, &r.dir);
                 if denom.abs() < 1e-8 {
@@ -318,34 +351,52 @@ impl HitObject {
                 rec.set_face_normal(r, *normal);
                 (rec, true)
             }
-            HitObject::Triangle { v1, v2, v3, mat,normal,bbox,
-                area:_, }=>{
+            HitObject::Triangle {
+                v1,
+                v2,
+                v3,
+                mat,
+                normal,
+                bbox: _,
+                area: _,
+            } => {
+                stats::count(|s| s.primitive_tests.triangle += 1);
                 let mut rec = HitRecord::new();
-                if Vec3::dot(&Vec3::unit_vector(r.ori-*v1),normal)<=0.0001{
-                    return (rec,false);
+                let denom = Vec3::dot(&r.dir, normal);
+                if denom.abs() < 1e-8 {
+                    return (rec, false);
                 }
-                let light_d=Vec3::dot(&(*v1-r.ori),normal)/Vec3::dot(&r.dir,normal);
-                if light_d<=0.0{
-                    return (rec,false);
+                let light_d = Vec3::dot(&(*v1 - r.ori), normal) / denom;
+                if !ray_t.surrounds(light_d) {
+                    return (rec, false);
                 }
                 rec.t = light_d;
                 rec.p = r.at(light_d);
                 rec.mat = mat.clone();
                 rec.set_face_normal(r, *normal);
- 
-                let a=Vec3::unit_vector(*v1-rec.p);
-                let b=Vec3::unit_vector(*v2-rec.p);
-                let c=Vec3::unit_vector(*v3-rec.p);
-                let sa=Vec3::unit_vector(Vec3::cross(&a,& b));
-                let sb=Vec3::unit_vector(Vec3::cross(&b,& c));
-                let sc=Vec3::unit_vector(Vec3::cross(&c,& a));
 
-                if !(Vec3::dot(&sa,&sb) >0.999 && Vec3::dot(&sb,&sc) >0.999 && Vec3::dot(&sc,&sa) >0.999){
-                    return (rec,false);
+                let a = Vec3::unit_vector(*v1 - rec.p);
+                let b = Vec3::unit_vector(*v2 - rec.p);
+                let c = Vec3::unit_vector(*v3 - rec.p);
+                let sa = Vec3::unit_vector(Vec3::cross(&a, &b));
+                let sb = Vec3::unit_vector(Vec3::cross(&b, &c));
+                let sc = Vec3::unit_vector(Vec3::cross(&c, &a));
+
+                if !(Vec3::dot(&sa, &sb) > 0.999
+                    && Vec3::dot(&sb, &sc) > 0.999
+                    && Vec3::dot(&sc, &sa) > 0.999)
+                {
+                    return (rec, false);
                 }
                 (rec, true)
             }
-            HitObject::Bvh { left, right, bbox } => {
+            HitObject::Bvh {
+                left,
+                right,
+                bbox,
+                area: _,
+            } => {
+                stats::count(|s| s.bvh_nodes_visited += 1);
                 if !bbox.hit(r, ray_t) {
                     return (HitRecord::new(), false);
                 }
@@ -439,6 +490,7 @@ impl HitObject {
                 neg_inv_density,
                 phase_function,
             } => {
+                stats::count(|s| s.primitive_tests.constant_medium += 1);
                 let enable_debug = false;
                 let _debugging = enable_debug && random_double_01() < 0.00001;
                 let (mut rec1, flag) = boundary.hit(r, &crate::rtweekend::interval::UNIVERSE);
@@ -482,10 +534,16 @@ impl HitObject {
                         mat: phase_function.clone(),
                         u: 0.0,
                         v: 0.0,
+                        object_id: 0,
                     },
                     true,
                 )
             }
+            HitObject::Tagged { object, id } => {
+                let (mut rec, flag) = object.hit(r, ray_t);
+                rec.object_id = *id;
+                (rec, flag)
+            }
         }
     }
     pub fn add(&mut self, object: HitObject) {
@@ -496,6 +554,8 @@ impl HitObject {
                 mat: _,
                 is_moving: _,
                 center_vec: _,
+                time0: _,
+                time1: _,
                 bbox: _,
             } => (),
             HitObject::Quad {
@@ -513,6 +573,7 @@ impl HitObject {
                 left: _,
                 right: _,
                 bbox: _,
+                area: _,
             } => (),
             HitObject::HittableList { objects, bbox } => {
                 objects.push(object.clone());
@@ -535,28 +596,151 @@ impl HitObject {
                 phase_function: _,
             } => (),
             HitObject::Triangle {
-                v1:_,
-                v2:_,
-                v3:_,
-                normal:_,
-                mat:_,
-                bbox:_,
-                area:_,
-            }=>(),
+                v1: _,
+                v2: _,
+                v3: _,
+                normal: _,
+                mat: _,
+                bbox: _,
+                area: _,
+            } => (),
+            HitObject::Tagged { object: _, id: _ } => (),
         }
     }
-    pub fn pdf_value(&self, ori: Point3, dir: Vec3) -> f64 {
+    // Surface area that random_from() spreads its points over.
+    pub fn area(&self) -> f64 {
         match self {
             HitObject::Sphere {
-                center_st,
+                center_st: _,
                 radius,
                 mat: _,
                 is_moving: _,
                 center_vec: _,
+                time0: _,
+                time1: _,
+                bbox: _,
+            } => 4.0 * std::f64::consts::PI * radius * radius,
+            HitObject::Quad {
+                q: _,
+                u: _,
+                v: _,
+                w: _,
+                mat: _,
+                bbox: _,
+                normal: _,
+                d: _,
+                area,
+            } => *area,
+            HitObject::Triangle {
+                v1: _,
+                v2: _,
+                v3: _,
+                mat: _,
+                normal: _,
+                bbox: _,
+                area,
+            } => *area,
+            HitObject::Bvh {
+                left: _,
+                right: _,
+                bbox: _,
+                area,
+            } => *area,
+            HitObject::HittableList { objects, bbox: _ } => objects.iter().map(|o| o.area()).sum(),
+            HitObject::Translate {
+                object,
+                offset: _,
+                bbox: _,
+            } => object.area(),
+            HitObject::Rotate {
+                object,
+                sin_theta: _,
+                cos_theta: _,
+                bbox: _,
+            } => object.area(),
+            HitObject::ConstantMedium {
+                boundary,
+                neg_inv_density: _,
+                phase_function: _,
+            } => boundary.area(),
+            HitObject::Tagged { object, id: _ } => object.area(),
+        }
+    }
+    // Estimated power of the light it gives off: pi times radiance times area for
+    // surfaces that emit from their front, summed over everything below.
+    pub fn power(&self) -> f64 {
+        match self {
+            HitObject::Sphere {
+                center_st: _,
+                radius: _,
+                mat,
+                is_moving: _,
+                center_vec: _,
+                time0: _,
+                time1: _,
+                bbox: _,
+            }
+            | HitObject::Quad {
+                q: _,
+                u: _,
+                v: _,
+                w: _,
+                mat,
+                bbox: _,
+                normal: _,
+                d: _,
+                area: _,
+            }
+            | HitObject::Triangle {
+                v1: _,
+                v2: _,
+                v3: _,
+                mat,
+                normal: _,
+                bbox: _,
+                area: _,
+            } => std::f64::consts::PI * self.area() * luminance(&mat.average_emission()),
+            HitObject::Bvh {
+                left,
+                right,
+                bbox: _,
+                area: _,
+            } => left.power() + right.power(),
+            HitObject::HittableList { objects, bbox: _ } => objects.iter().map(|o| o.power()).sum(),
+            HitObject::Translate {
+                object,
+                offset: _,
+                bbox: _,
+            } => object.power(),
+            HitObject::Rotate {
+                object,
+                sin_theta: _,
+                cos_theta: _,
+                bbox: _,
+            } => object.power(),
+            // Media scatter light but never give any off
+            HitObject::ConstantMedium {
+                boundary: _,
+                neg_inv_density: _,
+                phase_function: _,
+            } => 0.0,
+            HitObject::Tagged { object, id: _ } => object.power(),
+        }
+    }
+    pub fn pdf_value(&self, ori: Point3, dir: Vec3, time: f64) -> f64 {
+        match self {
+            HitObject::Sphere {
+                center_st: _,
+                radius,
+                mat: _,
+                is_moving: _,
+                center_vec: _,
+                time0: _,
+                time1: _,
                 bbox: _,
             } => {
                 let (_rec, flag) = self.hit(
-                    &Ray { ori, dir, tm: 0.0 },
+                    &Ray { ori, dir, tm: time },
                     &Interval {
                         min: 0.001,
                         max: INF,
@@ -565,7 +749,8 @@ impl HitObject {
                 if !flag {
                     return 0.0;
                 }
-                let cos_theta_max = (1.0 - radius * radius / (*center_st - ori).sq_length()).sqrt();
+                let center = self.cur_center(time);
+                let cos_theta_max = (1.0 - radius * radius / (center - ori).sq_length()).sqrt();
                 let solid_angle = 2.0 * std::f64::consts::PI * (1.0 - cos_theta_max);
                 1.0 / solid_angle
             }
@@ -581,7 +766,7 @@ impl HitObject {
                 area,
             } => {
                 let (rec, flag) = self.hit(
-                    &Ray { ori, dir, tm: 0.0 },
+                    &Ray { ori, dir, tm: time },
                     &Interval {
                         min: 0.001,
                         max: INF,
@@ -595,59 +780,102 @@ impl HitObject {
                 distance_squared / (cosine * area)
             }
             HitObject::Triangle {
-                v1:_,
-                v2:_,
-                v3:_,
-                normal:_,
-                mat:_,
-                bbox:_,
+                v1: _,
+                v2: _,
+                v3: _,
+                normal,
+                mat: _,
+                bbox: _,
                 area,
-            }=>{
-                1.0/area
+            } => {
+                let (rec, flag) = self.hit(
+                    &Ray { ori, dir, tm: time },
+                    &Interval {
+                        min: 0.001,
+                        max: INF,
+                    },
+                );
+                if !flag {
+                    return 0.0;
+                }
+                let distance_squared = rec.t * rec.t * dir.sq_length();
+                let cosine = (Vec3::dot(&dir, normal) / dir.length()).abs();
+                distance_squared / (cosine * area)
             }
             HitObject::Bvh {
-                left: _,
-                right: _,
-                bbox: _,
-            } => 0.0,
+                left,
+                right,
+                bbox,
+                area,
+            } => {
+                // Children are picked by area, so the density mixes theirs the same way
+                let ray = Ray { ori, dir, tm: time };
+                if *area <= 0.0
+                    || !bbox.hit(
+                        &ray,
+                        &Interval {
+                            min: 0.001,
+                            max: INF,
+                        },
+                    )
+                {
+                    return 0.0;
+                }
+                (left.area() * left.pdf_value(ori, dir, time)
+                    + right.area() * right.pdf_value(ori, dir, time))
+                    / area
+            }
             HitObject::HittableList { objects, bbox: _ } => {
+                if objects.is_empty() {
+                    return 0.0;
+                }
                 let weight = 1.0 / objects.len() as f64;
                 let mut sum = 0.0;
                 for object in objects {
                     //& or not
-                    sum += object.pdf_value(ori, dir) * weight;
+                    sum += object.pdf_value(ori, dir, time) * weight;
                 }
                 sum
             }
             HitObject::Translate {
-                object: _,
-                offset: _,
+                object,
+                offset,
                 bbox: _,
-            } => 0.0,
+            } => object.pdf_value(ori - *offset, dir, time),
             HitObject::Rotate {
-                object: _,
-                sin_theta: _,
-                cos_theta: _,
+                object,
+                sin_theta,
+                cos_theta,
                 bbox: _,
-            } => 0.0,
+            } => {
+                // Turning both leaves solid angles, and so the density, unchanged
+                object.pdf_value(
+                    rotate_y(&ori, -sin_theta, *cos_theta),
+                    rotate_y(&dir, -sin_theta, *cos_theta),
+                    time,
+                )
+            }
             HitObject::ConstantMedium {
-                boundary: _,
+                boundary,
                 neg_inv_density: _,
                 phase_function: _,
-            } => 0.0,
+            } => boundary.pdf_value(ori, dir, time),
+            HitObject::Tagged { object, id: _ } => object.pdf_value(ori, dir, time),
         }
     }
-    pub fn random_from(&self, ori: Point3) -> Vec3 {
+    pub fn random_from(&self, ori: Point3, time: f64) -> Vec3 {
         match self {
             HitObject::Sphere {
-                center_st,
+                center_st: _,
                 radius,
                 mat: _,
                 is_moving: _,
                 center_vec: _,
+                time0: _,
+                time1: _,
                 bbox: _,
             } => {
-                let dir = *center_st - ori;
+                let dir = self.cur_center(time) - ori;
                 let distance_squared = dir.sq_length();
                 let uvw = Onb::build_from_w(dir);
                 uvw.local(&hittable::random_to_sphere(*radius, distance_squared))
@@ -667,41 +895,59 @@ impl HitObject {
                 p - ori
             }
             HitObject::Triangle {
-                v1:_,
-                v2:_,
-                v3:_,
-                normal:_,
-                mat:_,
-                bbox:_,
-                area:_,
-            }=>{
-                Vec3::new()
+                v1,
+                v2,
+                v3,
+                normal: _,
+                mat: _,
+                bbox: _,
+                area: _,
+            } => {
+                // Uniform over the area: folding the unit square by sqrt keeps it even
+                let s = random_double_01().sqrt();
+                let r = random_double_01();
+                let p = *v1 * (1.0 - s) + *v2 * (s * (1.0 - r)) + *v3 * (s * r);
+                p - ori
             }
             HitObject::Bvh {
-                left: _,
-                right: _,
+                left,
+                right,
                 bbox: _,
-            } => Vec3::new(),
+                area,
+            } => {
+                if random_double_01() * area < left.area() {
+                    left.random_from(ori, time)
+                } else {
+                    right.random_from(ori, time)
+                }
+            }
             HitObject::HittableList { objects, bbox: _ } => {
+                if objects.is_empty() {
+                    return Vec3::new();
+                }
                 let int_size = objects.len() as i32;
-                objects[random_int(0, int_size - 1) as usize].random_from(ori)
+                objects[random_int(0, int_size - 1) as usize].random_from(ori, time)
             }
             HitObject::Translate {
-                object: _,
-                offset: _,
+                object,
+                offset,
                 bbox: _,
-            } => Vec3::new(),
+            } => object.random_from(ori - *offset, time),
             HitObject::Rotate {
-                object: _,
-                sin_theta: _,
-                cos_theta: _,
+                object,
+                sin_theta,
+                cos_theta,
                 bbox: _,
-            } => Vec3::new(),
+            } => {
+                let dir = object.random_from(rotate_y(&ori, -sin_theta, *cos_theta), time);
+                rotate_y(&dir, *sin_theta, *cos_theta)
+            }
             HitObject::ConstantMedium {
-                boundary: _,
+                boundary,
                 neg_inv_density: _,
                 phase_function: _,
-            } => Vec3::new(),
+            } => boundary.random_from(ori, time),
+            HitObject::Tagged { object, id: _ } => object.random_from(ori, time),
         }
     }
 
@@ -744,3 +990,195 @@ impl HitObject {
     }
     */
 }
+
+// v turned about +y the way build_rotate turns its object; -sin_theta turns it back.
+fn rotate_y(v: &Vec3, sin_theta: f64, cos_theta: f64) -> Vec3 {
+    Vec3 {
+        e: [
+            cos_theta * v.e[0] + sin_theta * v.e[2],
+            v.e[1],
+            -sin_theta * v.e[0] + cos_theta * v.e[2],
+        ],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hittable_list::hittable::{
+        build_quad, build_rotate, build_translate, build_triangle, bvh_node,
+    };
+    use crate::hittable_list::texture::Texture;
+    use crate::rtweekend::seed_random;
+    use crate::rtweekend::vec3::Color;
+
+    fn white() -> Material {
+        Material::Lambertian {
+            tex: Box::new(Texture::SolidColor {
+                albedo: Color { e: [1.0; 3] },
+            }),
+        }
+    }
+
+    // Averages one over pdf_value for directions drawn with random_from, which comes
+    // to the solid angle the object covers when the two agree.
+    fn estimate_solid_angle(object: &HitObject, ori: Point3) -> f64 {
+        seed_random(11);
+        let n = 100_000;
+        let mut sum = 0.0;
+        for _ in 0..n {
+            let dir = object.random_from(ori, 0.0);
+            let pdf = object.pdf_value(ori, dir, 0.0);
+            assert!(pdf > 0.0, "sampled direction {:?} has no density", dir.e);
+            sum += 1.0 / pdf;
+        }
+        sum / n as f64
+    }
+
+    // Van Oosterom and Strackee.
+    fn triangle_solid_angle(a: Point3, b: Point3, c: Point3, ori: Point3) -> f64 {
+        let (a, b, c) = (a - ori, b - ori, c - ori);
+        let (la, lb, lc) = (a.length(), b.length(), c.length());
+        let numerator = Vec3::dot(&a, &Vec3::cross(&b, &c)).abs();
+        let denominator =
+            la * lb * lc + Vec3::dot(&a, &b) * lc + Vec3::dot(&a, &c) * lb + Vec3::dot(&b, &c) * la;
+        2.0 * numerator.atan2(denominator)
+    }
+
+    fn assert_close(estimate: f64, expected: f64) {
+        assert!(
+            (estimate / expected - 1.0).abs() < 0.01,
+            "estimated solid angle {}, expected {}",
+            estimate,
+            expected
+        );
+    }
+
+    #[test]
+    fn triangle_pdf_matches_its_sampling() {
+        let (a, b, c) = (
+            Point3 {
+                e: [-1.0, 2.0, -0.5],
+            },
+            Point3 { e: [1.5, 2.5, 0.0] },
+            Point3 { e: [0.0, 1.8, 1.2] },
+        );
+        let ori = Point3 { e: [0.2, 0.0, 0.3] };
+        let triangle = build_triangle(a, b, c, white());
+        assert_close(
+            estimate_solid_angle(&triangle, ori),
+            triangle_solid_angle(a, b, c, ori),
+        );
+    }
+
+    // A flat mesh of triangles of very different sizes, which the BVH picks by area.
+    #[test]
+    fn mesh_bvh_pdf_matches_its_sampling() {
+        let xs = [-1.0, -0.7, 1.5];
+        let zs = [-1.0, 0.2, 1.0];
+        let corner = |i: usize, j: usize| Point3 {
+            e: [xs[i], 2.0, zs[j]],
+        };
+        let mut triangles = Vec::new();
+        let mut expected = 0.0;
+        let ori = Point3 {
+            e: [0.3, 0.0, -0.1],
+        };
+        for i in 0..2 {
+            for j in 0..2 {
+                for (a, b, c) in [
+                    (corner(i, j), corner(i + 1, j), corner(i + 1, j + 1)),
+                    (corner(i, j), corner(i + 1, j + 1), corner(i, j + 1)),
+                ] {
+                    triangles.push(build_triangle(a, b, c, white()));
+                    expected += triangle_solid_angle(a, b, c, ori);
+                }
+            }
+        }
+        let size = triangles.len();
+        let mesh = bvh_node(&mut triangles, 0, size);
+        assert_close(estimate_solid_angle(&mesh, ori), expected);
+    }
+
+    // Rotating then moving a quad light has to leave both its sampling and its
+    // density exactly as for a quad built where it ends up.
+    #[test]
+    fn transformed_quad_samples_like_one_built_in_place() {
+        let (q, u, v) = (
+            Point3 {
+                e: [-0.5, 0.0, -0.5],
+            },
+            Vec3 { e: [1.0, 0.0, 0.0] },
+            Vec3 { e: [0.0, 0.4, 0.8] },
+        );
+        let (angle, offset): (f64, Vec3) = (
+            35.0,
+            Vec3 {
+                e: [0.5, 2.0, -1.0],
+            },
+        );
+        let transformed =
+            build_translate(&build_rotate(&build_quad(q, u, v, white()), angle), offset);
+        let (sin_theta, cos_theta) = angle.to_radians().sin_cos();
+        let turn = |p: Vec3| rotate_y(&p, sin_theta, cos_theta);
+        let in_place = build_quad(turn(q) + offset, turn(u), turn(v), white());
+
+        let ori = Point3 {
+            e: [0.1, -0.5, 0.4],
+        };
+        let estimate = estimate_solid_angle(&transformed, ori);
+        let expected = estimate_solid_angle(&in_place, ori);
+        assert!(
+            (estimate / expected - 1.0).abs() < 0.01,
+            "transformed quad covers {}, the same quad built in place {}",
+            estimate,
+            expected
+        );
+
+        seed_random(5);
+        for _ in 0..1000 {
+            let dir = transformed.random_from(ori, 0.0);
+            let (a, b) = (
+                transformed.pdf_value(ori, dir, 0.0),
+                in_place.pdf_value(ori, dir, 0.0),
+            );
+            assert!((a / b - 1.0).abs() < 1e-9, "pdfs {} and {} differ", a, b);
+        }
+    }
+
+    fn leaves(object: &HitObject) -> usize {
+        match object {
+            HitObject::Bvh {
+                left,
+                right,
+                bbox: _,
+                area: _,
+            } => leaves(left) + leaves(right),
+            HitObject::HittableList { objects, bbox: _ } => objects.iter().map(leaves).sum(),
+            _ => 1,
+        }
+    }
+
+    // Odd counts leave nodes with a single object, which must not appear twice.
+    #[test]
+    fn bvh_holds_every_object_once() {
+        for n in 1..8 {
+            let mut triangles: Vec<HitObject> = (0..n)
+                .map(|i| {
+                    let x = i as f64 * 2.0;
+                    build_triangle(
+                        Point3 { e: [x, 0.0, 0.0] },
+                        Point3 {
+                            e: [x + 1.0, 0.0, 0.0],
+                        },
+                        Point3 { e: [x, 1.0, 0.0] },
+                        white(),
+                    )
+                })
+                .collect();
+            let bvh = bvh_node(&mut triangles, 0, n);
+            assert_eq!(leaves(&bvh), n);
+            assert!((bvh.area() - 0.5 * n as f64).abs() < 1e-12);
+        }
+    }
+}
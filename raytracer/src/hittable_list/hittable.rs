@@ -26,6 +26,7 @@ pub struct HitRecord {
     pub v: f64,
     pub front_face: bool,
     pub mat: Material,
+    pub object_id: u32, // Id of the tagged scene object that was hit, 0 if untagged
 }
 impl HitRecord {
     pub fn set_face_normal(&mut self, r: &Ray, outward_normal: Vec3) {
@@ -50,6 +51,7 @@ impl HitRecord {
                     albedo: Color::new(),
                 }),
             },
+            object_id: 0,
         }
     }
 }
@@ -92,7 +94,8 @@ pub fn bvh_node(objects: &mut Vec<HitObject>, start: usize, end: usize) -> HitOb
 
     if object_span == 1 {
         left = objects[start].clone();
-        right = objects[start].clone();
+        // Not the same object again, which every ray would then be tested against twice
+        right = new_hittable_list();
     } else if object_span == 2 {
         left = objects[start].clone();
         right = objects[start + 1].clone();
@@ -109,6 +112,7 @@ pub fn bvh_node(objects: &mut Vec<HitObject>, start: usize, end: usize) -> HitOb
         right = bvh_node(objects, mid, end);
     }
     HitObject::Bvh {
+        area: left.area() + right.area(),
         left: Box::new(left),
         right: Box::new(right),
         bbox,
@@ -120,6 +124,7 @@ pub fn build_sphere(
     radius: f64,
     mat: Material,
     is_moving: bool,
+    move_time: (f64, f64),
 ) -> HitObject {
     let v = Vec3 {
         e: [radius, radius, radius],
@@ -138,6 +143,8 @@ pub fn build_sphere(
         mat,
         is_moving,
         center_vec,
+        time0: move_time.0,
+        time1: move_time.1,
         bbox,
     }
 }
@@ -307,15 +314,21 @@ pub fn build_constant_medium(boundary: &HitObject, density: f64, tex: &Texture)
         },
     }
 }
-pub fn build_triangle(a:Point3,b:Point3,c:Point3,mat: Material)->HitObject{
-    let mut n = Vec3::cross(&(a-b), &(b-c));    
-    if n.e[0]>0.0{//n.e[0]
-        n=-n;
-    }
+pub fn build_triangle(a: Point3, b: Point3, c: Point3, mat: Material) -> HitObject {
+    // Counter-clockwise winding faces the front, as in .obj files
+    let n = Vec3::cross(&(a - b), &(b - c));
     let normal = Vec3::unit_vector(n);
-    let bbox=merge(&point_to_aabb(&a, &b),&point_to_aabb(&a, &c));
-    let area=n.length()/2.0;
-    HitObject::Triangle { v1: a, v2: b, v3: c, mat,normal,area, bbox }
+    let bbox = merge(&point_to_aabb(&a, &b), &point_to_aabb(&a, &c));
+    let area = n.length() / 2.0;
+    HitObject::Triangle {
+        v1: a,
+        v2: b,
+        v3: c,
+        mat,
+        normal,
+        area,
+        bbox,
+    }
 }
 pub fn random_to_sphere(radius: f64, distance_squared: f64) -> Vec3 {
     let r1 = random_double_01();
@@ -36,9 +36,9 @@ impl Material {
         match self {
             Material::Lambertian { tex } => {
                 srec.attenuation = tex.value(rec.u, rec.v, &rec.p);
-                srec.pdf_ptr = Box::new(Pdf::Cosinepdf {
+                srec.pdf = Pdf::Cosinepdf {
                     uvw: Onb::build_from_w(rec.normal),
-                });
+                };
                 srec.skip_pdf = false;
                 true
             }
@@ -84,7 +84,7 @@ impl Material {
             Material::Diffuselight { tex: _ } => false,
             Material::Isotropic { tex } => {
                 srec.attenuation = tex.value(rec.u, rec.v, &rec.p);
-                srec.pdf_ptr = Box::new(Pdf::Spherepdf);
+                srec.pdf = Pdf::Spherepdf;
                 srec.skip_pdf = false;
                 true
             }
@@ -107,6 +107,25 @@ impl Material {
             Material::Isotropic { tex: _ } => Color::new(),
         }
     }
+    // Radiance leaving the front of an emitter, averaged over its texture.
+    pub fn average_emission(&self) -> Color {
+        match self {
+            Material::Diffuselight { tex } => tex.average(),
+            _ => Color::new(),
+        }
+    }
+    // Surface colour without lighting, for the albedo pass.
+    pub fn albedo(&self, rec: &HitRecord) -> Color {
+        match self {
+            Material::Lambertian { tex }
+            | Material::Diffuselight { tex }
+            | Material::Isotropic { tex } => tex.value(rec.u, rec.v, &rec.p),
+            Material::Metal { albedo, fuzz: _ } => *albedo,
+            Material::Dielectric {
+                refraction_index: _,
+            } => Color { e: [1.0, 1.0, 1.0] },
+        }
+    }
     pub fn scattering_pdf(&self, _r_in: &Ray, rec: &HitRecord, scattered: &Ray) -> f64 {
         match self {
             Material::Lambertian { tex: _ } => {
@@ -134,7 +153,7 @@ fn reflectance(cosine: f64, refraction_index: f64) -> f64 {
 }
 pub struct ScatterRecord {
     pub attenuation: Color,
-    pub pdf_ptr: Box<Pdf>,
+    pub pdf: Pdf<'static>,
     pub skip_pdf: bool,
     pub skip_pdf_ray: Ray,
 }
@@ -13,7 +13,7 @@ pub enum Texture {
         even: Box<Texture>,
         odd: Box<Texture>,
     },*/
-    _Imagetexture { image: Box<RtwImage> },
+    Imagetexture { image: Box<RtwImage> },
     Noisetexture { noise: Box<Perlin>, scale: f64 },
 }
 impl Texture {
@@ -36,12 +36,12 @@ impl Texture {
                     odd.value(u, v, p)
                 }
             }*/
-            Texture::_Imagetexture { image } => {
+            Texture::Imagetexture { image } => {
                 let interval = Interval { min: 0.0, max: 1.0 };
                 let u = interval.clamp(u);
                 let v = 1.0 - interval.clamp(v);
-                let i = (u * image.width as f64) as u32;
-                let j = (v * image.height as f64) as u32;
+                let i = ((u * image.width as f64) as u32).min(image.width - 1);
+                let j = ((v * image.height as f64) as u32).min(image.height - 1);
                 Color {
                     e: image.pixel_data(i, j),
                 }
@@ -52,4 +52,24 @@ impl Texture {
             }
         }
     }
+    // Mean value over the texture, for guessing how bright a light is.
+    pub fn average(&self) -> Color {
+        match self {
+            Texture::SolidColor { albedo } => *albedo,
+            Texture::Imagetexture { image } => {
+                let mut sum = Color::new();
+                for j in 0..image.height {
+                    for i in 0..image.width {
+                        sum = sum
+                            + Color {
+                                e: image.pixel_data(i, j),
+                            };
+                    }
+                }
+                sum / (image.width * image.height).max(1) as f64
+            }
+            // 0.5 * (1 + sin) averages to 0.5
+            Texture::Noisetexture { noise: _, scale: _ } => Color { e: [0.5, 0.5, 0.5] },
+        }
+    }
 }
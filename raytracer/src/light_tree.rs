@@ -0,0 +1,296 @@
+use std::cmp::Ordering;
+
+use crate::aabb::{merge, Aabb};
+use crate::hittable_list::HitObject;
+use crate::rtweekend::interval::Interval;
+use crate::rtweekend::random_double_01;
+use crate::rtweekend::ray::Ray;
+use crate::rtweekend::vec3::{Point3, Vec3};
+use crate::rtweekend::INF;
+
+// The scene's lights in a binary tree over their bounds. A light is picked by walking
+// down from the root, at every node taking each side in proportion to its power over
+// its squared distance from the shading point, so bright and nearby lights get most
+// samples and one pick costs a walk down the tree rather than a pass over every light.
+#[derive(Clone, Debug)]
+pub enum LightTree {
+    Empty,
+    Leaf {
+        light: HitObject,
+        power: f64,
+        bbox: Aabb,
+    },
+    Node {
+        left: Box<LightTree>,
+        right: Box<LightTree>,
+        power: f64,
+        bbox: Aabb,
+    },
+}
+
+pub fn build_light_tree(lights: Vec<HitObject>) -> LightTree {
+    // Objects marked as lights that give off nothing, like a glass ball that paths
+    // should be steered towards, are still sampled as if they were a typical light
+    let powers: Vec<f64> = lights
+        .iter()
+        .map(|light| light.power())
+        .map(|power| if power.is_finite() { power } else { 0.0 })
+        .collect();
+    let emitting: Vec<f64> = powers.iter().copied().filter(|&p| p > 0.0).collect();
+    let typical = if emitting.is_empty() {
+        1.0
+    } else {
+        emitting.iter().sum::<f64>() / emitting.len() as f64
+    };
+    let mut leaves: Vec<LightTree> = lights
+        .into_iter()
+        .zip(powers)
+        .map(|(light, power)| LightTree::Leaf {
+            bbox: light.bounding_box(),
+            power: if power > 0.0 { power } else { typical },
+            light,
+        })
+        .collect();
+    light_tree_node(&mut leaves)
+}
+
+// Splits the lights at the middle of their centres along the longest axis, like bvh_node.
+fn light_tree_node(leaves: &mut [LightTree]) -> LightTree {
+    match leaves.len() {
+        0 => return LightTree::Empty,
+        1 => return leaves[0].clone(),
+        _ => (),
+    }
+    let mut bbox = crate::aabb::EMPTY;
+    for leaf in leaves.iter() {
+        bbox = merge(&bbox, leaf.bbox());
+    }
+    let axis = bbox.longest_axis() as usize;
+    leaves.sort_by(|a, b| {
+        center(a.bbox()).e[axis]
+            .partial_cmp(&center(b.bbox()).e[axis])
+            .unwrap_or(Ordering::Equal)
+    });
+    let (left, right) = leaves.split_at_mut(leaves.len() / 2);
+    let (left, right) = (light_tree_node(left), light_tree_node(right));
+    LightTree::Node {
+        power: left.power() + right.power(),
+        left: Box::new(left),
+        right: Box::new(right),
+        bbox,
+    }
+}
+
+impl LightTree {
+    pub fn is_empty(&self) -> bool {
+        matches!(self, LightTree::Empty)
+    }
+    pub fn len(&self) -> usize {
+        match self {
+            LightTree::Empty => 0,
+            LightTree::Leaf {
+                light: _,
+                power: _,
+                bbox: _,
+            } => 1,
+            LightTree::Node {
+                left,
+                right,
+                power: _,
+                bbox: _,
+            } => left.len() + right.len(),
+        }
+    }
+    fn power(&self) -> f64 {
+        match self {
+            LightTree::Empty => 0.0,
+            LightTree::Leaf {
+                light: _,
+                power,
+                bbox: _,
+            }
+            | LightTree::Node {
+                left: _,
+                right: _,
+                power,
+                bbox: _,
+            } => *power,
+        }
+    }
+    fn bbox(&self) -> &Aabb {
+        match self {
+            LightTree::Empty => &crate::aabb::EMPTY,
+            LightTree::Leaf {
+                light: _,
+                power: _,
+                bbox,
+            }
+            | LightTree::Node {
+                left: _,
+                right: _,
+                power: _,
+                bbox,
+            } => bbox,
+        }
+    }
+    // How much the lights below are guessed to add at p. Points inside the bounds are
+    // kept at the distance of its corners, where the inverse square would blow up.
+    fn importance(&self, p: &Point3) -> f64 {
+        let bbox = self.bbox();
+        let half_diagonal = Vec3 {
+            e: [0, 1, 2].map(|a| bbox.b[a].size() / 2.0),
+        };
+        let distance_squared = (center(bbox) - *p).sq_length();
+        self.power() / distance_squared.max(half_diagonal.sq_length())
+    }
+    // Chance of going down the left side at a node, seen from p.
+    fn left_probability(left: &LightTree, right: &LightTree, p: &Point3) -> f64 {
+        let (l, r) = (left.importance(p), right.importance(p));
+        if l + r > 0.0 {
+            l / (l + r)
+        } else {
+            0.5
+        }
+    }
+    // Solid angle density of random_from() towards dir, summed over every light the
+    // direction could have come from; subtrees the ray misses are skipped.
+    pub fn pdf_value(&self, ori: Point3, dir: Vec3, time: f64) -> f64 {
+        match self {
+            LightTree::Empty => 0.0,
+            LightTree::Leaf {
+                light,
+                power: _,
+                bbox: _,
+            } => light.pdf_value(ori, dir, time),
+            LightTree::Node {
+                left,
+                right,
+                power: _,
+                bbox,
+            } => {
+                let ray = Ray { ori, dir, tm: time };
+                if !bbox.hit(
+                    &ray,
+                    &Interval {
+                        min: 0.001,
+                        max: INF,
+                    },
+                ) {
+                    return 0.0;
+                }
+                let p = LightTree::left_probability(left, right, &ori);
+                p * left.pdf_value(ori, dir, time) + (1.0 - p) * right.pdf_value(ori, dir, time)
+            }
+        }
+    }
+    pub fn random_from(&self, ori: Point3, time: f64) -> Vec3 {
+        match self {
+            LightTree::Empty => Vec3::new(),
+            LightTree::Leaf {
+                light,
+                power: _,
+                bbox: _,
+            } => light.random_from(ori, time),
+            LightTree::Node {
+                left,
+                right,
+                power: _,
+                bbox: _,
+            } => {
+                if random_double_01() < LightTree::left_probability(left, right, &ori) {
+                    left.random_from(ori, time)
+                } else {
+                    right.random_from(ori, time)
+                }
+            }
+        }
+    }
+}
+
+fn center(bbox: &Aabb) -> Point3 {
+    Point3 {
+        e: [0, 1, 2].map(|a| (bbox.b[a].min + bbox.b[a].max) / 2.0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::f64::consts::PI;
+
+    use super::*;
+    use crate::hittable_list::hittable::build_sphere;
+    use crate::hittable_list::material::Material;
+    use crate::hittable_list::texture::Texture;
+    use crate::rtweekend::seed_random;
+    use crate::rtweekend::vec3::Color;
+
+    fn sphere_light(center: [f64; 3], radius: f64, brightness: f64) -> HitObject {
+        let mat = Material::Diffuselight {
+            tex: Box::new(Texture::SolidColor {
+                albedo: Color { e: [brightness; 3] },
+            }),
+        };
+        build_sphere(
+            Point3 { e: center },
+            Vec3::new(),
+            radius,
+            mat,
+            false,
+            (0.0, 1.0),
+        )
+    }
+
+    // Sampling directions with random_from and weighting each by one over pdf_value
+    // has to add up to the solid angle of every light, which only holds when
+    // pdf_value is the density random_from actually samples with.
+    #[test]
+    fn pdf_value_matches_how_often_random_from_picks_each_light() {
+        seed_random(7);
+        let spheres = [
+            ([0.0, 0.0, -5.0], 0.5, 10.0),
+            ([4.0, 0.0, -4.0], 1.0, 1.0),
+            ([-3.0, 2.0, -6.0], 0.3, 50.0),
+            ([0.0, -8.0, 0.0], 2.0, 2.0),
+            ([6.0, 5.0, 3.0], 0.8, 0.5),
+        ];
+        let lights: Vec<HitObject> = spheres
+            .iter()
+            .map(|&(c, r, b)| sphere_light(c, r, b))
+            .collect();
+        let tree = build_light_tree(lights.clone());
+        assert_eq!(tree.len(), spheres.len());
+
+        let ori = Point3 { e: [0.5, 0.0, 0.0] };
+        let n = 200_000;
+        let mut solid_angles = vec![0.0; spheres.len()];
+        for _ in 0..n {
+            let dir = tree.random_from(ori, 0.0);
+            let pdf = tree.pdf_value(ori, dir, 0.0);
+            assert!(pdf > 0.0);
+            let hit = lights
+                .iter()
+                .position(|l| l.pdf_value(ori, dir, 0.0) > 0.0)
+                .expect("sampled direction misses every light");
+            solid_angles[hit] += 1.0 / pdf / n as f64;
+        }
+        for (i, &(c, r, _)) in spheres.iter().enumerate() {
+            let distance = (Point3 { e: c } - ori).length();
+            let expected = 2.0 * PI * (1.0 - (1.0 - r * r / (distance * distance)).sqrt());
+            assert!(
+                (solid_angles[i] / expected - 1.0).abs() < 0.05,
+                "light {}: estimated solid angle {}, expected {}",
+                i,
+                solid_angles[i],
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn empty_tree_samples_nothing() {
+        let tree = build_light_tree(Vec::new());
+        assert!(tree.is_empty());
+        let ori = Point3::new();
+        assert_eq!(tree.pdf_value(ori, Vec3 { e: [0.0, 0.0, 1.0] }, 0.0), 0.0);
+    }
+}
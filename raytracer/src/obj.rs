@@ -1,72 +1,64 @@
-use crate::{hittable_list::{hittable::new_hittable_list, HitObject}, rtweekend::{random_double, vec3::Point3}};
-use crate::rtweekend::vec3::Color;
-use crate::hittable_list::texture::Texture;
-use crate::hittable_list::material::Material;
+use std::path::Path;
+
 use crate::hittable_list::hittable::build_triangle;
-pub fn read_from_obj()->HitObject{
-    let mut world=new_hittable_list();
+use crate::hittable_list::hittable::bvh_node;
+use crate::hittable_list::material::Material;
+use crate::hittable_list::HitObject;
+use crate::rtweekend::random_double;
+use crate::rtweekend::vec3::Color;
+use crate::rtweekend::vec3::Point3;
 
-    let obj_file = "raytracer/sources/rose.obj";
-    let (models, materials) =
-        tobj::load_obj(
-            &obj_file,
-            &tobj::LoadOptions::default()
-        )
-        .expect("Failed to OBJ load file");
-    let materials = materials.expect("Failed to load MTL file");
+// Loads every face of an .obj file as triangles and wraps them in their own BVH.
+// Without a material each face gets a random rose-tinted metal, like the final scene.
+pub fn read_from_obj(
+    path: &Path,
+    scale: f64,
+    mat: Option<&Material>,
+) -> Result<HitObject, tobj::LoadError> {
+    let (models, _materials) = tobj::load_obj(
+        path,
+        &tobj::LoadOptions {
+            triangulate: true,
+            ..Default::default()
+        },
+    )?;
 
-    let mut p=Vec::new();
-    for (i, m) in models.iter().enumerate() {
+    let mut triangles = Vec::new();
+    for m in models.iter() {
         let mesh = &m.mesh;
 
-        assert!(mesh.positions.len() % 3 == 0);
-
+        let mut p = Vec::new();
         for vtx in 0..mesh.positions.len() / 3 {
-            let v1=Point3{
-                e:[mesh.positions[3 * vtx] as f64+0.0,
-                mesh.positions[3 * vtx + 1] as f64-20.0,
-                mesh.positions[3 * vtx + 2] as f64+0.0]};
-            p.push(v1*3.0);
+            let v = Point3 {
+                e: [
+                    mesh.positions[3 * vtx] as f64,
+                    mesh.positions[3 * vtx + 1] as f64,
+                    mesh.positions[3 * vtx + 2] as f64,
+                ],
+            };
+            p.push(v * scale);
         }
-        let mut next_face = 0;
-        for face in 0..(mesh.face_arities.len()-1) {
-            let end = next_face + mesh.face_arities[face] as usize;
-    
-            let face_indices = &mesh.indices[next_face..end];
-            
-            let g=random_double(0.0,0.4);
-            let b=random_double(0.0,0.4);
-            let red = Material::Lambertian {
-                tex: Box::new(Texture::SolidColor {
+        for face in mesh.indices.chunks_exact(3) {
+            let face_mat = match mat {
+                Some(mat) => mat.clone(),
+                None => Material::Metal {
                     albedo: Color {
-                        e: [0.9, g, b],
+                        e: [0.9, random_double(0.0, 0.4), random_double(0.0, 0.4)],
                     },
-                }),
-            };
-            let aluminum = Material::Metal {
-                albedo: Color {
-                    e: [0.9, g, b],
+                    fuzz: 0.0,
                 },
-                fuzz: 0.0,
             };
-            world.add(build_triangle(p[face_indices[0] as usize],
-                p[face_indices[1] as usize],
-                p[face_indices[2] as usize],aluminum));
-    
-            next_face = end;
+            triangles.push(build_triangle(
+                p[face[0] as usize],
+                p[face[1] as usize],
+                p[face[2] as usize],
+                face_mat,
+            ));
         }
     }
-/*    for i in 0..(p.len()-2){
-        let g=random_double(0.0,0.2);
-        let b=random_double(0.0,0.2);
-        let red = Material::Lambertian {
-            tex: Box::new(Texture::SolidColor {
-                albedo: Color {
-                    e: [0.9, g, b],
-                },
-            }),
-        };
-        world.add(build_triangle(p[i],p[i+1],p[i+2],red));
-    }*/
-    world
-}
\ No newline at end of file
+    if triangles.is_empty() {
+        return Err(tobj::LoadError::GenericFailure);
+    }
+    let size = triangles.len();
+    Ok(bvh_node(&mut triangles, 0, size))
+}
@@ -1,23 +1,29 @@
-use crate::hittable_list::HitObject;
+use crate::environment::Environment;
+use crate::light_tree::LightTree;
 use crate::onb::Onb;
 use crate::rtweekend::random_double_01;
 use crate::rtweekend::vec3::Point3;
 use crate::rtweekend::vec3::Vec3;
-pub enum Pdf {
+// Borrows what it samples from, so building one per bounce costs no allocation.
+pub enum Pdf<'a> {
     Spherepdf,
     Cosinepdf {
         uvw: Onb,
     },
-    Hittablepdf {
-        objects: Box<HitObject>,
+    Lightpdf {
+        lights: &'a LightTree,
         ori: Point3,
+        time: f64, // Where moving objects are
+    },
+    Environmentpdf {
+        environment: &'a Environment,
     },
     Mixturepdf {
-        p: [Box<Pdf>; 2],
+        p: [&'a Pdf<'a>; 2],
     },
 }
 
-impl Pdf {
+impl<'a> Pdf<'a> {
     pub fn value(&self, dir: Vec3) -> f64 {
         match self {
             Pdf::Spherepdf => 1.0 / (4.0 * std::f64::consts::PI),
@@ -25,7 +31,8 @@ impl Pdf {
                 let cosine_theta = Vec3::dot(&Vec3::unit_vector(dir), &uvw.axis[2]);
                 (cosine_theta / std::f64::consts::PI).max(0.0)
             }
-            Pdf::Hittablepdf { objects, ori } => objects.pdf_value(*ori, dir),
+            Pdf::Lightpdf { lights, ori, time } => lights.pdf_value(*ori, dir, *time),
+            Pdf::Environmentpdf { environment } => environment.pdf_value(&dir),
             Pdf::Mixturepdf { p } => p[0].value(dir) * 0.5 + p[1].value(dir) * 0.5,
         }
     }
@@ -33,7 +40,8 @@ impl Pdf {
         match self {
             Pdf::Spherepdf => Vec3::random_unit_vector(),
             Pdf::Cosinepdf { uvw } => uvw.local(&Vec3::random_cosine_direction()),
-            Pdf::Hittablepdf { objects, ori } => objects.random_from(*ori),
+            Pdf::Lightpdf { lights, ori, time } => lights.random_from(*ori, *time),
+            Pdf::Environmentpdf { environment } => environment.random_direction(),
             Pdf::Mixturepdf { p } => {
                 if random_double_01() < 0.5 {
                     p[0].generate()
@@ -0,0 +1,270 @@
+use std::error::Error;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use clap::ValueEnum;
+use exr::prelude::{AnyChannel, AnyChannels, FlatSamples, Image, SmallVec, WritableImage};
+use image::codecs::hdr::HdrEncoder;
+use image::{ImageBuffer, Rgb};
+
+use crate::rtweekend::color::write_color;
+use crate::rtweekend::color::{luminance, ToneMap};
+use crate::rtweekend::color::{to_display, to_srgb};
+use crate::rtweekend::vec3::Color;
+
+// Linear radiance as it comes out of the renderer, row-major from the top-left pixel.
+#[derive(Clone)]
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub pixels: Vec<Color>,
+}
+impl Framebuffer {
+    pub fn new(width: u32, height: u32) -> Framebuffer {
+        Framebuffer {
+            width,
+            height,
+            pixels: vec![Color::new(); (width * height) as usize],
+        }
+    }
+    pub fn get(&self, x: u32, y: u32) -> Color {
+        self.pixels[(y * self.width + x) as usize]
+    }
+    pub fn set(&mut self, x: u32, y: u32, c: Color) {
+        self.pixels[(y * self.width + x) as usize] = c;
+    }
+    // Copy of this image inside a black one of width x height, with its top-left
+    // pixel at (x, y).
+    pub fn embed(&self, x: u32, y: u32, width: u32, height: u32) -> Framebuffer {
+        let mut out = Framebuffer::new(width, height);
+        for j in 0..self.height {
+            for i in 0..self.width {
+                out.set(x + i, y + j, self.get(i, j));
+            }
+        }
+        out
+    }
+}
+
+// Scales by 2^exposure stops and nothing else, for output that stays linear.
+pub fn expose(fb: &Framebuffer, exposure: f64) -> Framebuffer {
+    let scale = exposure.exp2();
+    let mut out = fb.clone();
+    for p in &mut out.pixels {
+        *p = *p * scale;
+    }
+    out
+}
+
+// Scales by 2^exposure stops and applies the tone curve. Without an explicit white
+// point, extended Reinhard maps the brightest pixel to 1.
+pub fn tone_map(fb: &Framebuffer, op: ToneMap, exposure: f64, white: Option<f64>) -> Framebuffer {
+    let scale = exposure.exp2();
+    let white = white.unwrap_or_else(|| {
+        fb.pixels
+            .iter()
+            .map(|c| luminance(c) * scale)
+            .filter(|l| l.is_finite())
+            .fold(1.0, f64::max)
+    });
+    let mut out = fb.clone();
+    for p in &mut out.pixels {
+        *p = op.apply(&(*p * scale), white);
+    }
+    out
+}
+
+// False-colour map of per-pixel sample counts, blue for the fewest and red for the most.
+pub fn sample_heatmap(counts: &[u32], width: u32, height: u32) -> Framebuffer {
+    let min = counts.iter().copied().min().unwrap_or(0) as f64;
+    let max = counts.iter().copied().max().unwrap_or(0) as f64;
+    let mut fb = Framebuffer::new(width, height);
+    for (p, n) in fb.pixels.iter_mut().zip(counts) {
+        let t = if max > min {
+            (*n as f64 - min) / (max - min)
+        } else {
+            0.0
+        };
+        *p = Color {
+            e: [t, 1.0 - (2.0 * t - 1.0).abs(), 1.0 - t],
+        };
+    }
+    fb
+}
+
+// Shading normals mapped from [-1, 1] to [0, 1] per component.
+pub fn normal_preview(fb: &Framebuffer) -> Framebuffer {
+    let mut out = fb.clone();
+    for p in &mut out.pixels {
+        *p = (*p + Color { e: [1.0, 1.0, 1.0] }) * 0.5;
+    }
+    out
+}
+
+// Depth scaled so the farthest hit is white; misses stay black.
+pub fn depth_preview(fb: &Framebuffer) -> Framebuffer {
+    let max = fb.pixels.iter().map(|c| c.e[0]).fold(0.0, f64::max);
+    let mut out = fb.clone();
+    if max > 0.0 {
+        for p in &mut out.pixels {
+            *p = *p / max;
+        }
+    }
+    out
+}
+
+// A stable pseudo-random colour per object id, black for the background.
+pub fn id_preview(fb: &Framebuffer) -> Framebuffer {
+    let mut out = fb.clone();
+    for p in &mut out.pixels {
+        let id = p.e[0] as u32;
+        if id == 0 {
+            continue;
+        }
+        let h = id.wrapping_mul(0x9e37_79b1);
+        *p = Color {
+            e: [
+                (h >> 24) as f64 / 255.0,
+                ((h >> 16) & 0xff) as f64 / 255.0,
+                ((h >> 8) & 0xff) as f64 / 255.0,
+            ],
+        };
+    }
+    out
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Format {
+    /// ASCII P3 portable pixmap, tone mapped and sRGB encoded
+    Ppm,
+    /// 8-bit PNG, tone mapped and sRGB encoded
+    Png,
+    /// 16-bit PNG, tone mapped and sRGB encoded
+    Png16,
+    /// Radiance RGBE, linear
+    Hdr,
+    /// Portable float map, linear
+    Pfm,
+    /// OpenEXR 32-bit float, linear
+    Exr,
+}
+impl Format {
+    pub fn from_path(path: &Path) -> Option<Format> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "ppm" => Some(Format::Ppm),
+            "png" => Some(Format::Png),
+            "hdr" => Some(Format::Hdr),
+            "pfm" => Some(Format::Pfm),
+            "exr" => Some(Format::Exr),
+            _ => None,
+        }
+    }
+    // Whether the format keeps linear floats rather than display values.
+    pub fn is_float(self) -> bool {
+        matches!(self, Format::Hdr | Format::Pfm | Format::Exr)
+    }
+}
+
+pub fn write_image(fb: &Framebuffer, path: &Path, format: Format) -> Result<(), Box<dyn Error>> {
+    match format {
+        Format::Ppm => write_ppm(fb, path)?,
+        Format::Png => {
+            let mut buf = Vec::with_capacity(fb.pixels.len() * 3);
+            for c in &fb.pixels {
+                buf.extend(to_display(c).iter().map(|x| (256.0 * x) as u8));
+            }
+            ImageBuffer::<Rgb<u8>, _>::from_raw(fb.width, fb.height, buf)
+                .ok_or("framebuffer size mismatch")?
+                .save_with_format(path, image::ImageFormat::Png)?;
+        }
+        Format::Png16 => {
+            let mut buf = Vec::with_capacity(fb.pixels.len() * 3);
+            for c in &fb.pixels {
+                buf.extend(to_srgb(c).iter().map(|x| (65535.0 * x).round() as u16));
+            }
+            ImageBuffer::<Rgb<u16>, _>::from_raw(fb.width, fb.height, buf)
+                .ok_or("framebuffer size mismatch")?
+                .save_with_format(path, image::ImageFormat::Png)?;
+        }
+        Format::Hdr => {
+            let data: Vec<Rgb<f32>> = fb
+                .pixels
+                .iter()
+                .map(|c| Rgb([c.e[0] as f32, c.e[1] as f32, c.e[2] as f32]))
+                .collect();
+            let file = BufWriter::new(File::create(path)?);
+            HdrEncoder::new(file).encode(&data, fb.width as usize, fb.height as usize)?;
+        }
+        Format::Pfm => write_pfm(fb, path)?,
+        Format::Exr => {
+            exr::prelude::write_rgb_file(path, fb.width as usize, fb.height as usize, |x, y| {
+                let c = fb.get(x as u32, y as u32);
+                (c.e[0] as f32, c.e[1] as f32, c.e[2] as f32)
+            })?;
+        }
+    }
+    Ok(())
+}
+
+// Writes several framebuffers of the same size into one OpenEXR file. Each entry
+// gives a layer name and one channel name per component to keep; channels are
+// stored as "layer.channel", or just "channel" when the layer name is empty.
+pub fn write_exr_layers(
+    layers: &[(&str, &Framebuffer, &[&str])],
+    path: &Path,
+) -> Result<(), Box<dyn Error>> {
+    let (width, height) = match layers.first() {
+        Some((_, fb, _)) => (fb.width, fb.height),
+        None => return Err("no layers to write".into()),
+    };
+    let mut channels = SmallVec::new();
+    for (layer, fb, names) in layers {
+        if (fb.width, fb.height) != (width, height) {
+            return Err(format!("layer {} has a different size", layer).into());
+        }
+        for (k, name) in names.iter().enumerate() {
+            let samples = fb.pixels.iter().map(|c| c.e[k] as f32).collect();
+            let full_name = if layer.is_empty() {
+                name.to_string()
+            } else {
+                format!("{}.{}", layer, name)
+            };
+            channels.push(AnyChannel::new(
+                full_name.as_str(),
+                FlatSamples::F32(samples),
+            ));
+        }
+    }
+    Image::from_channels(
+        (width as usize, height as usize),
+        AnyChannels::sort(channels),
+    )
+    .write()
+    .to_file(path)?;
+    Ok(())
+}
+
+fn write_ppm(fb: &Framebuffer, path: &Path) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    writeln!(file, "P3\n{} {}\n255", fb.width, fb.height)?;
+    for c in &fb.pixels {
+        write_color(c, &mut file)?;
+    }
+    file.flush()
+}
+
+// PFM stores little-endian floats bottom row first; a negative scale marks little-endian.
+fn write_pfm(fb: &Framebuffer, path: &Path) -> std::io::Result<()> {
+    let mut file = BufWriter::new(File::create(path)?);
+    write!(file, "PF\n{} {}\n-1.0\n", fb.width, fb.height)?;
+    for y in (0..fb.height).rev() {
+        for x in 0..fb.width {
+            for c in fb.get(x, y).e {
+                file.write_all(&(c as f32).to_le_bytes())?;
+            }
+        }
+    }
+    file.flush()
+}
@@ -1,5 +1,10 @@
 use crate::hittable_list::texture::Texture;
+use crate::rtweekend::color::srgb_to_linear;
+use image::codecs::hdr::HdrDecoder;
 use image::{GenericImageView, Pixel, Rgba};
+use std::error::Error;
+use std::fs::File;
+use std::io::BufReader;
 use std::path::Path;
 
 #[derive(Clone, Debug)]
@@ -13,8 +18,8 @@ impl RtwImage {
         self.float_pixels[(y * self.width + x) as usize]
     }
 }
-pub fn _load_image_to_float_array<P: AsRef<Path>>(path: P) -> Texture {
-    let img = image::open(path).expect("Failed to open image");
+pub fn load_image_to_float_array<P: AsRef<Path>>(path: P) -> Result<Texture, image::ImageError> {
+    let img = image::open(path)?;
     let (width, height) = img.dimensions();
 
     let mut float_pixels = Vec::with_capacity((width * height) as usize);
@@ -27,11 +32,122 @@ pub fn _load_image_to_float_array<P: AsRef<Path>>(path: P) -> Texture {
 
         float_pixels.push([r, g, b]);
     }
-    Texture::_Imagetexture {
+    Ok(Texture::Imagetexture {
         image: Box::new(RtwImage {
             width,
             height,
             float_pixels,
         }),
+    })
+}
+
+// Loads an image as linear radiance: Radiance .hdr, OpenEXR and PFM files keep their
+// float values, anything else is decoded as sRGB and linearised.
+pub fn load_hdr_image<P: AsRef<Path>>(path: P) -> Result<RtwImage, Box<dyn Error>> {
+    let path = path.as_ref();
+    let ext = path
+        .extension()
+        .map(|e| e.to_string_lossy().to_ascii_lowercase());
+    match ext.as_deref() {
+        Some("hdr") => {
+            let decoder = HdrDecoder::new(BufReader::new(File::open(path)?))?;
+            let meta = decoder.metadata();
+            let float_pixels = decoder
+                .read_image_hdr()?
+                .iter()
+                .map(|p| [p[0] as f64, p[1] as f64, p[2] as f64])
+                .collect();
+            Ok(RtwImage {
+                width: meta.width,
+                height: meta.height,
+                float_pixels,
+            })
+        }
+        Some("exr") => {
+            let image = exr::prelude::read_first_rgba_layer_from_file(
+                path,
+                |size, _| RtwImage {
+                    width: size.width() as u32,
+                    height: size.height() as u32,
+                    float_pixels: vec![[0.0; 3]; size.area()],
+                },
+                |image: &mut RtwImage, pos, (r, g, b, _): (f32, f32, f32, f32)| {
+                    let k = pos.y() * image.width as usize + pos.x();
+                    image.float_pixels[k] = [r as f64, g as f64, b as f64];
+                },
+            )?;
+            Ok(image.layer_data.channel_data.pixels)
+        }
+        Some("pfm") => load_pfm(&std::fs::read(path)?),
+        _ => {
+            // Through 16 bits so 16-bit PNGs keep their precision
+            let img = image::open(path)?.to_rgb16();
+            let (width, height) = img.dimensions();
+            let float_pixels = img
+                .pixels()
+                .map(|p| [0, 1, 2].map(|c| srgb_to_linear(p[c] as f64 / 65535.0)))
+                .collect();
+            Ok(RtwImage {
+                width,
+                height,
+                float_pixels,
+            })
+        }
+    }
+}
+
+// Portable float map: a text header of "PF" (RGB) or "Pf" (grey), the size and a scale
+// whose sign gives the byte order, then f32 rows from the bottom up.
+fn load_pfm(data: &[u8]) -> Result<RtwImage, Box<dyn Error>> {
+    let mut fields = Vec::new();
+    let mut pos = 0;
+    while fields.len() < 4 {
+        while pos < data.len() && data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        let start = pos;
+        while pos < data.len() && !data[pos].is_ascii_whitespace() {
+            pos += 1;
+        }
+        if start == pos {
+            return Err("truncated PFM header".into());
+        }
+        fields.push(std::str::from_utf8(&data[start..pos])?);
+    }
+    // A single whitespace byte separates the header from the data
+    pos += 1;
+    let channels = match fields[0] {
+        "PF" => 3,
+        "Pf" => 1,
+        magic => return Err(format!("not a PFM file (magic {:?})", magic).into()),
+    };
+    let width: u32 = fields[1].parse()?;
+    let height: u32 = fields[2].parse()?;
+    let little_endian = fields[3].parse::<f64>()? < 0.0;
+    let count = width as usize * height as usize * channels;
+    let body = data.get(pos..pos + count * 4).ok_or("truncated PFM data")?;
+    let values: Vec<f64> = body
+        .chunks_exact(4)
+        .map(|b| {
+            let b = [b[0], b[1], b[2], b[3]];
+            if little_endian {
+                f32::from_le_bytes(b) as f64
+            } else {
+                f32::from_be_bytes(b) as f64
+            }
+        })
+        .collect();
+    let mut float_pixels = Vec::with_capacity(width as usize * height as usize);
+    for y in (0..height as usize).rev() {
+        let row = &values[y * width as usize * channels..(y + 1) * width as usize * channels];
+        float_pixels.extend(row.chunks_exact(channels).map(|c| match channels {
+            3 => [c[0], c[1], c[2]],
+            _ => [c[0]; 3],
+        }));
     }
+    Ok(RtwImage {
+        width,
+        height,
+        float_pixels,
+    })
 }
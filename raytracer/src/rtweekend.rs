@@ -1,17 +1,67 @@
-use rand::Rng;
+use std::cell::RefCell;
 
 pub mod color;
+pub mod distribution;
 pub mod interval;
 pub mod ray;
 pub mod vec3;
 pub const INF: f64 = 1e18;
 
+// PCG32 (XSH RR), small and fast enough to be reseeded for every camera sample.
+#[derive(Clone, Debug)]
+pub struct Pcg32 {
+    state: u64,
+    inc: u64,
+}
+impl Pcg32 {
+    pub fn new(seed: u64, stream: u64) -> Pcg32 {
+        let mut rng = Pcg32 {
+            state: 0,
+            inc: (stream << 1) | 1,
+        };
+        rng.next_u32();
+        rng.state = rng.state.wrapping_add(seed);
+        rng.next_u32();
+        rng
+    }
+    pub fn next_u32(&mut self) -> u32 {
+        let old = self.state;
+        self.state = old.wrapping_mul(6364136223846793005).wrapping_add(self.inc);
+        let xorshifted = (((old >> 18) ^ old) >> 27) as u32;
+        let rot = (old >> 59) as u32;
+        xorshifted.rotate_right(rot)
+    }
+    pub fn next_f64(&mut self) -> f64 {
+        self.next_u32() as f64 / 4294967296.0
+    }
+}
+
+fn splitmix64(mut x: u64) -> u64 {
+    x = x.wrapping_add(0x9e3779b97f4a7c15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d049bb133111eb);
+    x ^ (x >> 31)
+}
+
+thread_local! {
+    static RNG: RefCell<Pcg32> = RefCell::new(Pcg32::new(rand::random(), rand::random()));
+}
+
 pub fn degrees_to_radians(degree: f64) -> f64 {
     degree * std::f64::consts::PI / 180.0
 }
+// Reseeds the generator of the calling thread; every thread starts from entropy.
+pub fn seed_random(seed: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = Pcg32::new(splitmix64(seed), 0));
+}
+// Switches the calling thread to the stream of one camera sample. Everything that
+// draws numbers while tracing it (get_ray, scatter, Pdf::generate, media) then
+// depends only on the seed, the pixel and the sample, not on the thread or tile order.
+pub fn seed_sample(seed: u64, pixel: u64, sample: u64) {
+    RNG.with(|rng| *rng.borrow_mut() = Pcg32::new(splitmix64(seed ^ splitmix64(pixel)), sample));
+}
 pub fn random_double_01() -> f64 {
-    let mut rng = rand::thread_rng();
-    rng.gen_range(0.0..1.0)
+    RNG.with(|rng| rng.borrow_mut().next_f64())
 }
 pub fn random_double(min: f64, max: f64) -> f64 {
     min + random_double_01() * (max - min)
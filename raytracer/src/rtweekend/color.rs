@@ -1,39 +1,100 @@
 use crate::rtweekend::interval::Interval;
 use crate::rtweekend::vec3::Color;
-use std::fs::File;
+use clap::ValueEnum;
 use std::io::Write;
 
-fn linear_to_gamma(linear_component: f64) -> f64 {
-    if linear_component > 0.0 {
-        return linear_component.sqrt();
+// sRGB transfer function: linear below 0.0031308, a 1/2.4 power curve above.
+fn linear_to_srgb(linear_component: f64) -> f64 {
+    if linear_component <= 0.0 {
+        return 0.0;
     }
-    0.0
+    if linear_component <= 0.0031308 {
+        return 12.92 * linear_component;
+    }
+    1.055 * linear_component.powf(1.0 / 2.4) - 0.055
 }
-pub fn write_color(pixel_color: &Color, file: &mut File) {
-    let mut r = pixel_color.e[0];
-    let mut g = pixel_color.e[1];
-    let mut b = pixel_color.e[2];
-
-    if r.is_nan() {
-        r = 0.0;
+pub fn srgb_to_linear(srgb_component: f64) -> f64 {
+    if srgb_component <= 0.04045 {
+        return srgb_component / 12.92;
     }
-    if g.is_nan() {
-        g = 0.0;
+    ((srgb_component + 0.055) / 1.055).powf(2.4)
+}
+pub fn luminance(c: &Color) -> f64 {
+    0.2126 * c.e[0] + 0.7152 * c.e[1] + 0.0722 * c.e[2]
+}
+// Operators that squeeze linear radiance into the [0, 1] display range.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum ToneMap {
+    /// Hard clip at 1
+    Clamp,
+    /// L / (1 + L) on luminance
+    Reinhard,
+    /// Reinhard with a white point that maps to 1
+    ReinhardExtended,
+    /// Hable's Uncharted 2 filmic curve
+    Hable,
+    /// Narkowicz's fit of the ACES filmic curve
+    Aces,
+}
+impl ToneMap {
+    // white is the luminance that should end up at 1, only used by ReinhardExtended.
+    pub fn apply(&self, c: &Color, white: f64) -> Color {
+        match self {
+            ToneMap::Clamp => *c,
+            ToneMap::Reinhard => scale_luminance(c, |l| l / (1.0 + l)),
+            ToneMap::ReinhardExtended => {
+                let w2 = white * white;
+                scale_luminance(c, |l| l * (1.0 + l / w2) / (1.0 + l))
+            }
+            ToneMap::Hable => {
+                const EXPOSURE_BIAS: f64 = 2.0;
+                const WHITE: f64 = 11.2;
+                let scale = 1.0 / hable(WHITE);
+                Color {
+                    e: c.e.map(|x| hable(x * EXPOSURE_BIAS) * scale),
+                }
+            }
+            ToneMap::Aces => Color {
+                e: c.e.map(|x| {
+                    let x = x.max(0.0);
+                    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+                }),
+            },
+        }
     }
-    if b.is_nan() {
-        b = 0.0;
+}
+// Maps luminance through f and scales the colour to match, which keeps hue intact.
+fn scale_luminance(c: &Color, f: impl Fn(f64) -> f64) -> Color {
+    let l = luminance(c);
+    if l <= 0.0 {
+        return Color::new();
     }
+    *c * (f(l) / l)
+}
+fn hable(x: f64) -> f64 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
 
-    r = linear_to_gamma(r);
-    g = linear_to_gamma(g);
-    b = linear_to_gamma(b);
-
+// sRGB-encoded components in [0, 1].
+pub fn to_srgb(pixel_color: &Color) -> [f64; 3] {
+    pixel_color.e.map(|c| {
+        let c = if c.is_nan() { 0.0 } else { c };
+        linear_to_srgb(c).clamp(0.0, 1.0)
+    })
+}
+// sRGB-encoded components in [0, 1), ready to be quantised by truncating.
+pub fn to_display(pixel_color: &Color) -> [f64; 3] {
     let intensity = Interval {
         min: 0.000,
         max: 0.999,
     };
-    let rbyte = (256.0 * intensity.clamp(r)) as u32;
-    let gbyte = (256.0 * intensity.clamp(g)) as u32;
-    let bbyte = (256.0 * intensity.clamp(b)) as u32;
-    writeln!(file, "{} {} {}", rbyte, gbyte, bbyte).unwrap();
+    to_srgb(pixel_color).map(|c| intensity.clamp(c))
+}
+pub fn write_color<W: Write>(pixel_color: &Color, file: &mut W) -> std::io::Result<()> {
+    let [r, g, b] = to_display(pixel_color);
+    let rbyte = (256.0 * r) as u32;
+    let gbyte = (256.0 * g) as u32;
+    let bbyte = (256.0 * b) as u32;
+    writeln!(file, "{} {} {}", rbyte, gbyte, bbyte)
 }
@@ -0,0 +1,149 @@
+// Piecewise-constant distribution over [0, 1) with one bin per weight.
+#[derive(Clone, Debug)]
+pub struct Distribution1D {
+    cdf: Vec<f64>, // cdf[i] is the probability of landing before bin i, cdf[n] = 1
+}
+impl Distribution1D {
+    // Negative and non-finite weights count as zero; all-zero weights sample uniformly.
+    pub fn new(weights: &[f64]) -> Distribution1D {
+        let n = weights.len().max(1);
+        let mut cdf = Vec::with_capacity(n + 1);
+        cdf.push(0.0);
+        let mut total = 0.0;
+        for w in weights {
+            total += if w.is_finite() { w.max(0.0) } else { 0.0 };
+            cdf.push(total);
+        }
+        if total > 0.0 {
+            for c in &mut cdf {
+                *c /= total;
+            }
+        } else {
+            cdf = (0..=n).map(|i| i as f64 / n as f64).collect();
+        }
+        Distribution1D { cdf }
+    }
+    fn bins(&self) -> usize {
+        self.cdf.len() - 1
+    }
+    // Maps a uniform u in [0, 1) to a point in [0, 1) and its bin.
+    pub fn sample(&self, u: f64) -> (f64, usize) {
+        let i = (self.cdf.partition_point(|&c| c <= u) - 1).min(self.bins() - 1);
+        let width = self.cdf[i + 1] - self.cdf[i];
+        let offset = if width > 0.0 {
+            (u - self.cdf[i]) / width
+        } else {
+            0.0
+        };
+        ((i as f64 + offset.clamp(0.0, 1.0)) / self.bins() as f64, i)
+    }
+    // Density of sample() at x, relative to the uniform density on [0, 1).
+    pub fn pdf(&self, x: f64) -> f64 {
+        let n = self.bins();
+        let i = ((x * n as f64) as usize).min(n - 1);
+        (self.cdf[i + 1] - self.cdf[i]) * n as f64
+    }
+}
+
+// Distribution over the unit square from a grid of weights, stored row by row:
+// a row is picked by its total weight, then a column within that row.
+#[derive(Clone, Debug)]
+pub struct Distribution2D {
+    rows: Vec<Distribution1D>,
+    marginal: Distribution1D,
+}
+impl Distribution2D {
+    pub fn new(weights: &[f64], width: usize, height: usize) -> Distribution2D {
+        let rows: Vec<Distribution1D> = weights
+            .chunks(width)
+            .take(height)
+            .map(Distribution1D::new)
+            .collect();
+        let row_totals: Vec<f64> = weights
+            .chunks(width)
+            .take(height)
+            .map(|row| {
+                row.iter()
+                    .filter(|w| w.is_finite())
+                    .map(|w| w.max(0.0))
+                    .sum()
+            })
+            .collect();
+        Distribution2D {
+            rows,
+            marginal: Distribution1D::new(&row_totals),
+        }
+    }
+    // Point in [0, 1)^2 with x across a row and y down the rows.
+    pub fn sample(&self, u1: f64, u2: f64) -> (f64, f64) {
+        let (y, row) = self.marginal.sample(u2);
+        let (x, _) = self.rows[row].sample(u1);
+        (x, y)
+    }
+    // Density of sample() at (x, y) over the unit square.
+    pub fn pdf(&self, x: f64, y: f64) -> f64 {
+        let row = ((y * self.rows.len() as f64) as usize).min(self.rows.len() - 1);
+        self.marginal.pdf(y) * self.rows[row].pdf(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sample_2d_lands_in_cells_as_often_as_pdf_says() {
+        let (width, height) = (4, 3);
+        // Rows of four, with empty cells and a row lighter than the others
+        let weights = [1.0, 2.0, 0.0, 5.0, 0.5, 0.0, 0.0, 0.5, 3.0, 1.0, 8.0, 2.0];
+        let dist = Distribution2D::new(&weights, width, height);
+
+        // A fine grid of (u1, u2) stands in for uniform random numbers
+        let n = 600;
+        let mut counts = vec![0; width * height];
+        for a in 0..n {
+            for b in 0..n {
+                let u1 = (a as f64 + 0.5) / n as f64;
+                let u2 = (b as f64 + 0.5) / n as f64;
+                let (x, y) = dist.sample(u1, u2);
+                assert!((0.0..1.0).contains(&x) && (0.0..1.0).contains(&y));
+                counts[(y * height as f64) as usize * width + (x * width as f64) as usize] += 1;
+            }
+        }
+        for row in 0..height {
+            for col in 0..width {
+                let x = (col as f64 + 0.5) / width as f64;
+                let y = (row as f64 + 0.5) / height as f64;
+                let expected = dist.pdf(x, y) / (width * height) as f64;
+                let frequency = counts[row * width + col] as f64 / (n * n) as f64;
+                assert!(
+                    (frequency - expected).abs() < 0.005,
+                    "cell ({}, {}): sampled {} of the time, pdf says {}",
+                    col,
+                    row,
+                    frequency,
+                    expected
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn pdf_2d_integrates_to_one() {
+        let weights = [0.0, 1.0, 4.0, 2.0, 0.0, 9.0];
+        let dist = Distribution2D::new(&weights, 3, 2);
+        let total: f64 = (0..3)
+            .flat_map(|col| (0..2).map(move |row| (col, row)))
+            .map(|(col, row)| dist.pdf((col as f64 + 0.5) / 3.0, (row as f64 + 0.5) / 2.0) / 6.0)
+            .sum();
+        assert!((total - 1.0).abs() < 1e-12);
+        assert_eq!(dist.pdf(0.5, 0.75), 0.0);
+    }
+
+    #[test]
+    fn all_zero_weights_sample_uniformly() {
+        let dist = Distribution1D::new(&[0.0, 0.0, 0.0, 0.0]);
+        assert_eq!(dist.sample(0.6), (0.6, 2));
+        assert_eq!(dist.pdf(0.3), 1.0);
+    }
+}
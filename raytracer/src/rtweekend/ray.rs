@@ -1,6 +1,7 @@
 use crate::rtweekend::vec3::Point3;
 use crate::rtweekend::vec3::Vec3;
 //use crate::rtweekend::vec3::Color;
+#[derive(Clone, Copy)]
 pub struct Ray {
     pub dir: Vec3,
     pub ori: Point3,
@@ -0,0 +1,933 @@
+pub mod animation;
+pub mod description;
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+
+use toml::Spanned;
+
+use crate::camera::{Adaptive, Aperture, Camera, Mis, Projection, ShutterCurve};
+use crate::delta_light::{
+    build_directional_light, build_point_light, build_spot_light, DeltaLight,
+};
+use crate::environment::{build_environment_map, build_sky, Environment};
+use crate::hittable_list::hittable::build_box;
+use crate::hittable_list::hittable::build_constant_medium;
+use crate::hittable_list::hittable::build_quad;
+use crate::hittable_list::hittable::build_rotate;
+use crate::hittable_list::hittable::build_sphere;
+use crate::hittable_list::hittable::build_translate;
+use crate::hittable_list::hittable::build_triangle;
+use crate::hittable_list::hittable::bvh_node;
+use crate::hittable_list::hittable::new_hittable_list;
+use crate::hittable_list::material::Material;
+use crate::hittable_list::perlin::Perlin;
+use crate::hittable_list::texture::Texture;
+use crate::hittable_list::HitObject;
+use crate::light_tree::{build_light_tree, LightTree};
+use crate::obj;
+use crate::rtw_image::{load_hdr_image, load_image_to_float_array};
+use crate::rtweekend::distribution::Distribution2D;
+use crate::rtweekend::vec3::Color;
+use crate::rtweekend::vec3::Vec3;
+use animation::{Animation, CameraTracks, Interpolation, ObjectTracks, SceneObject, Track};
+use description::{
+    ApertureDesc, EnvironmentDesc, InterpolationDesc, LightDesc, MaterialDesc, MisDesc, ObjectDesc,
+    ProjectionDesc, SceneFile, ShutterCurveDesc, TextureDesc,
+};
+
+pub struct Scene {
+    pub camera: Camera,
+    pub world: HitObject,
+    pub lights: LightTree,
+    pub animation: Option<Animation>,
+}
+impl Scene {
+    // The still scene at one frame of the animation. Objects without keys are reused,
+    // and the top-level BVH is only rebuilt when some object moves.
+    pub fn frame(&self, frame: u32) -> Scene {
+        let mut camera = self.camera.clone();
+        let (world, lights) = match &self.animation {
+            Some(animation) => {
+                let f = frame as f64;
+                animation
+                    .camera
+                    .apply(&mut camera, f, animation.interpolation);
+                if animation.static_world() {
+                    (self.world.clone(), self.lights.clone())
+                } else {
+                    assemble(animation.objects.iter().map(|o| {
+                        let object = match &o.tracks {
+                            Some(tracks) => tracks.apply(&o.object, f, animation.interpolation),
+                            None => o.object.clone(),
+                        };
+                        (object, o.light)
+                    }))
+                }
+            }
+            None => (self.world.clone(), self.lights.clone()),
+        };
+        Scene {
+            camera,
+            world,
+            lights,
+            animation: None,
+        }
+    }
+}
+
+// World BVH and light tree from top-level objects and whether each one is a light.
+fn assemble(objects: impl Iterator<Item = (HitObject, bool)>) -> (HitObject, LightTree) {
+    let mut world = new_hittable_list();
+    let mut lights = Vec::new();
+    for (object, light) in objects {
+        if light {
+            lights.push(object.clone());
+        }
+        world.add(object);
+    }
+    let mut objects = world.get_objects();
+    let size = objects.len();
+    (bvh_node(&mut objects, 0, size), build_light_tree(lights))
+}
+
+#[derive(Debug)]
+pub struct SceneError {
+    pub file: String,
+    pub line: usize,
+    pub field: String,
+    pub message: String,
+}
+impl fmt::Display for SceneError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}: {}: {}",
+            self.file, self.line, self.field, self.message
+        )
+    }
+}
+impl std::error::Error for SceneError {}
+
+pub fn load<P: AsRef<Path>>(path: P) -> Result<Scene, SceneError> {
+    let path = path.as_ref();
+    let file = path.display().to_string();
+    let src = fs::read_to_string(path).map_err(|e| SceneError {
+        file: file.clone(),
+        line: 0,
+        field: String::new(),
+        message: e.to_string(),
+    })?;
+    let base_dir = path.parent().unwrap_or_else(|| Path::new("")).to_path_buf();
+    parse(&src, &file, base_dir)
+}
+
+pub fn parse(src: &str, file: &str, base_dir: PathBuf) -> Result<Scene, SceneError> {
+    let desc: SceneFile =
+        serde_path_to_error::deserialize(toml::Deserializer::new(src)).map_err(|e| {
+            let field = e
+                .path()
+                .to_string()
+                .replace(".$__serde_spanned_private_value", "");
+            SceneError {
+                file: file.to_string(),
+                line: e.inner().span().map_or(0, |s| line_of(src, s.start)),
+                field,
+                message: e.inner().message().to_string(),
+            }
+        })?;
+
+    let mut builder = Builder {
+        src,
+        file,
+        base_dir,
+        textures: HashMap::new(),
+        materials: HashMap::new(),
+    };
+    for (name, tex) in &desc.textures {
+        let texture = builder.build_texture(tex, &format!("textures.{}", name))?;
+        builder.textures.insert(name.clone(), texture);
+    }
+    for (name, mat) in &desc.materials {
+        let material = builder.build_material(mat, &format!("materials.{}", name))?;
+        builder.materials.insert(name.clone(), material);
+    }
+
+    let mut objects = Vec::new();
+    for (i, object) in desc.objects.iter().enumerate() {
+        let field = format!("objects[{}]", i);
+        let built = builder.build_object(object, &field)?;
+        objects.push(SceneObject {
+            // Ids start at 1 so that 0 can stand for background in the object id pass
+            object: HitObject::Tagged {
+                object: Box::new(built),
+                id: i as u32 + 1,
+            },
+            light: object.get_ref().light,
+            tracks: builder.object_tracks(object, &field)?,
+        });
+    }
+    if objects.is_empty() {
+        return Err(builder.error(0..0, "objects", "scene has no objects".to_string()));
+    }
+    let (world, lights) = assemble(objects.iter().map(|o| (o.object.clone(), o.light)));
+
+    let animated = desc.animation.is_some() || objects.iter().any(|o| o.tracks.is_some());
+    let animation = if animated {
+        Some(builder.build_animation(&desc, objects)?)
+    } else {
+        None
+    };
+    let counts = [
+        (&desc.camera.width, "camera.width"),
+        (&desc.camera.samples_per_pixel, "camera.samples_per_pixel"),
+    ];
+    for (value, field) in counts {
+        if let Some(value) = value.as_ref().filter(|v| *v.get_ref() == 0) {
+            return Err(builder.error(value.span(), field, "must be at least 1".to_string()));
+        }
+    }
+    let mut camera = build_camera(&desc);
+    if let Some(aperture) = &desc.camera.aperture {
+        camera.aperture = builder.build_aperture(aperture, "camera.aperture")?;
+    }
+    if let Some(adaptive) = &desc.camera.adaptive {
+        if let Some(Err(message)) = camera.adaptive.map(|a| a.validate()) {
+            return Err(builder.error(adaptive.span(), "camera.adaptive.min_spp", message));
+        }
+    }
+    if let Some(environment) = &desc.camera.environment {
+        if desc.camera.background.is_some() {
+            return Err(builder.error(
+                environment.span(),
+                "camera.environment",
+                "cannot be combined with camera.background".to_string(),
+            ));
+        }
+        camera.background = builder.build_environment(environment, "camera.environment")?;
+    }
+    for (i, light) in desc.lights.iter().enumerate() {
+        let light = builder.build_delta_light(light, &format!("lights[{}]", i))?;
+        camera.delta_lights.push(light);
+    }
+    Ok(Scene {
+        camera,
+        world,
+        lights,
+        animation,
+    })
+}
+
+fn build_camera(desc: &SceneFile) -> Camera {
+    let c = &desc.camera;
+    let mut cam = Camera::new();
+    if let Some(x) = c.aspect_ratio {
+        cam.aspect_ratio = x;
+    }
+    if let Some(x) = &c.width {
+        cam.width = *x.get_ref();
+    }
+    if let Some(x) = &c.samples_per_pixel {
+        cam.samples_per_pixel = *x.get_ref();
+    }
+    if let Some(x) = c.max_depth {
+        cam.max_depth = x;
+    }
+    if let Some(x) = c.rr_depth {
+        cam.rr_depth = x;
+    }
+    if let Some(x) = c.mis {
+        cam.mis = match x {
+            MisDesc::Balance => Mis::Balance,
+            MisDesc::Power => Mis::Power,
+        };
+    }
+    if let Some(x) = c.background {
+        cam.background = Environment::Solid {
+            color: Color { e: x },
+        };
+    }
+    if let Some(x) = c.vfov {
+        cam.vfov = x;
+    }
+    if let Some(x) = c.lookfrom {
+        cam.lookfrom = Vec3 { e: x };
+    }
+    if let Some(x) = c.lookat {
+        cam.lookat = Vec3 { e: x };
+    }
+    if let Some(x) = c.vup {
+        cam.vup = Vec3 { e: x };
+    }
+    if let Some(x) = c.defocus_angle {
+        cam.defocus_angle = x;
+    }
+    if let Some(x) = c.focus_dist {
+        cam.focus_dist = x;
+    }
+    if let Some(x) = c.projection {
+        cam.projection = match x {
+            ProjectionDesc::Perspective => Projection::Perspective,
+            ProjectionDesc::Orthographic => Projection::Orthographic,
+            ProjectionDesc::Equirectangular => Projection::Equirectangular,
+            ProjectionDesc::FisheyeEquidistant => Projection::FisheyeEquidistant,
+            ProjectionDesc::FisheyeEquisolid => Projection::FisheyeEquisolid,
+        };
+    }
+    if let Some([open, close]) = c.shutter {
+        cam.shutter_open = open;
+        cam.shutter_close = close;
+    }
+    if let Some(x) = c.shutter_curve {
+        cam.shutter_curve = match x {
+            ShutterCurveDesc::Box => ShutterCurve::Box,
+            ShutterCurveDesc::Triangle => ShutterCurve::Triangle,
+        };
+    }
+    if let Some(x) = c.viewport_height {
+        cam.viewport_height = x;
+    }
+    if let Some(x) = c.fov {
+        cam.fov = x;
+    }
+    if let Some(x) = &c.adaptive {
+        cam.adaptive = Some(Adaptive {
+            min_spp: x.get_ref().min_spp,
+            threshold: x.get_ref().threshold,
+        });
+    }
+    cam
+}
+
+fn line_of(src: &str, offset: usize) -> usize {
+    src[..offset.min(src.len())].matches('\n').count() + 1
+}
+
+struct Builder<'a> {
+    src: &'a str,
+    file: &'a str,
+    base_dir: PathBuf,
+    textures: HashMap<String, Texture>,
+    materials: HashMap<String, Material>,
+}
+impl<'a> Builder<'a> {
+    fn error(&self, span: Range<usize>, field: &str, message: String) -> SceneError {
+        SceneError {
+            file: self.file.to_string(),
+            line: line_of(self.src, span.start),
+            field: field.to_string(),
+            message,
+        }
+    }
+    fn require<T: Copy>(
+        &self,
+        value: Option<T>,
+        span: &Range<usize>,
+        field: &str,
+        key: &str,
+    ) -> Result<T, SceneError> {
+        value.ok_or_else(|| {
+            self.error(
+                span.clone(),
+                &format!("{}.{}", field, key),
+                "missing field".to_string(),
+            )
+        })
+    }
+    fn require_vec3(
+        &self,
+        value: Option<[f64; 3]>,
+        span: &Range<usize>,
+        field: &str,
+        key: &str,
+    ) -> Result<Vec3, SceneError> {
+        Ok(Vec3 {
+            e: self.require(value, span, field, key)?,
+        })
+    }
+    fn texture_ref(&self, name: &Spanned<String>, field: &str) -> Result<Texture, SceneError> {
+        self.textures.get(name.get_ref()).cloned().ok_or_else(|| {
+            self.error(
+                name.span(),
+                field,
+                format!("unknown texture `{}`", name.get_ref()),
+            )
+        })
+    }
+    fn material_ref(&self, name: &Spanned<String>, field: &str) -> Result<Material, SceneError> {
+        self.materials.get(name.get_ref()).cloned().ok_or_else(|| {
+            self.error(
+                name.span(),
+                field,
+                format!("unknown material `{}`", name.get_ref()),
+            )
+        })
+    }
+    // Materials and media take either an inline `color` or a named `texture`.
+    fn color_or_texture(
+        &self,
+        color: Option<[f64; 3]>,
+        texture: &Option<Spanned<String>>,
+        span: &Range<usize>,
+        field: &str,
+    ) -> Result<Texture, SceneError> {
+        match (color, texture) {
+            (Some(_), Some(_)) => Err(self.error(
+                span.clone(),
+                field,
+                "`color` and `texture` are mutually exclusive".to_string(),
+            )),
+            (Some(albedo), None) => Ok(Texture::SolidColor {
+                albedo: Color { e: albedo },
+            }),
+            (None, Some(name)) => self.texture_ref(name, &format!("{}.texture", field)),
+            (None, None) => Err(self.error(
+                span.clone(),
+                &format!("{}.color", field),
+                "missing field".to_string(),
+            )),
+        }
+    }
+
+    fn build_texture(
+        &self,
+        tex: &Spanned<TextureDesc>,
+        field: &str,
+    ) -> Result<Texture, SceneError> {
+        let span = tex.span();
+        let t = tex.get_ref();
+        match t.kind.as_str() {
+            "solid" => Ok(Texture::SolidColor {
+                albedo: self.require_vec3(t.color, &span, field, "color")?,
+            }),
+            "image" => {
+                let path = t.path.as_ref().ok_or_else(|| {
+                    self.error(
+                        span.clone(),
+                        &format!("{}.path", field),
+                        "missing field".to_string(),
+                    )
+                })?;
+                load_image_to_float_array(self.base_dir.join(path)).map_err(|e| {
+                    self.error(span.clone(), &format!("{}.path", field), e.to_string())
+                })
+            }
+            "noise" => Ok(Texture::Noisetexture {
+                noise: Box::new(Perlin::build_perlin()),
+                scale: self.require(t.scale, &span, field, "scale")?,
+            }),
+            other => Err(self.error(
+                span,
+                &format!("{}.type", field),
+                format!("unknown texture type `{}`", other),
+            )),
+        }
+    }
+
+    fn build_material(
+        &self,
+        mat: &Spanned<MaterialDesc>,
+        field: &str,
+    ) -> Result<Material, SceneError> {
+        let span = mat.span();
+        let m = mat.get_ref();
+        match m.kind.as_str() {
+            "lambertian" => Ok(Material::Lambertian {
+                tex: Box::new(self.color_or_texture(m.color, &m.texture, &span, field)?),
+            }),
+            "metal" => Ok(Material::Metal {
+                albedo: self.require_vec3(m.albedo, &span, field, "albedo")?,
+                fuzz: m.fuzz.unwrap_or(0.0),
+            }),
+            "dielectric" => Ok(Material::Dielectric {
+                refraction_index: self.require(
+                    m.refraction_index,
+                    &span,
+                    field,
+                    "refraction_index",
+                )?,
+            }),
+            "diffuse_light" => Ok(Material::Diffuselight {
+                tex: Box::new(self.color_or_texture(m.color, &m.texture, &span, field)?),
+            }),
+            "isotropic" => Ok(Material::Isotropic {
+                tex: Box::new(self.color_or_texture(m.color, &m.texture, &span, field)?),
+            }),
+            other => Err(self.error(
+                span,
+                &format!("{}.type", field),
+                format!("unknown material type `{}`", other),
+            )),
+        }
+    }
+
+    fn object_material(
+        &self,
+        o: &ObjectDesc,
+        span: &Range<usize>,
+        field: &str,
+    ) -> Result<Material, SceneError> {
+        match &o.material {
+            Some(name) => self.material_ref(name, &format!("{}.material", field)),
+            None => Err(self.error(
+                span.clone(),
+                &format!("{}.material", field),
+                "missing field".to_string(),
+            )),
+        }
+    }
+
+    fn build_object(
+        &self,
+        obj: &Spanned<ObjectDesc>,
+        field: &str,
+    ) -> Result<HitObject, SceneError> {
+        let span = obj.span();
+        let o = obj.get_ref();
+        let mut object = match o.kind.as_str() {
+            "sphere" => build_sphere(
+                self.require_vec3(o.center, &span, field, "center")?,
+                Vec3 {
+                    e: o.motion.unwrap_or([0.0; 3]),
+                },
+                self.require(o.radius, &span, field, "radius")?,
+                self.object_material(o, &span, field)?,
+                o.motion.is_some(),
+                {
+                    let [t0, t1] = o.motion_time.unwrap_or([0.0, 1.0]);
+                    (t0, t1)
+                },
+            ),
+            "quad" => build_quad(
+                self.require_vec3(o.q, &span, field, "q")?,
+                self.require_vec3(o.u, &span, field, "u")?,
+                self.require_vec3(o.v, &span, field, "v")?,
+                self.object_material(o, &span, field)?,
+            ),
+            "box" => build_box(
+                &self.require_vec3(o.a, &span, field, "a")?,
+                &self.require_vec3(o.b, &span, field, "b")?,
+                &self.object_material(o, &span, field)?,
+            ),
+            "triangle" => build_triangle(
+                self.require_vec3(o.a, &span, field, "a")?,
+                self.require_vec3(o.b, &span, field, "b")?,
+                self.require_vec3(o.c, &span, field, "c")?,
+                self.object_material(o, &span, field)?,
+            ),
+            "obj" => {
+                let path = o.path.as_ref().ok_or_else(|| {
+                    self.error(
+                        span.clone(),
+                        &format!("{}.path", field),
+                        "missing field".to_string(),
+                    )
+                })?;
+                let mat = match &o.material {
+                    Some(name) => Some(self.material_ref(name, &format!("{}.material", field))?),
+                    None => None,
+                };
+                obj::read_from_obj(
+                    &self.base_dir.join(path),
+                    o.scale.unwrap_or(1.0),
+                    mat.as_ref(),
+                )
+                .map_err(|e| self.error(span.clone(), &format!("{}.path", field), e.to_string()))?
+            }
+            "constant_medium" => {
+                let boundary = o.boundary.as_ref().ok_or_else(|| {
+                    self.error(
+                        span.clone(),
+                        &format!("{}.boundary", field),
+                        "missing field".to_string(),
+                    )
+                })?;
+                build_constant_medium(
+                    &self.build_object(boundary, &format!("{}.boundary", field))?,
+                    self.require(o.density, &span, field, "density")?,
+                    &self.color_or_texture(o.color, &o.texture, &span, field)?,
+                )
+            }
+            other => {
+                return Err(self.error(
+                    span,
+                    &format!("{}.type", field),
+                    format!("unknown object type `{}`", other),
+                ))
+            }
+        };
+
+        if let Some(boundary) = &o.boundary {
+            if !boundary.get_ref().keys.is_empty() {
+                return Err(self.error(
+                    boundary.span(),
+                    &format!("{}.boundary.keys", field),
+                    "only top-level objects can be animated".to_string(),
+                ));
+            }
+        }
+        for (i, t) in o.transforms.iter().enumerate() {
+            object = match (t.translate, t.rotate_y) {
+                (Some(offset), None) => build_translate(&object, Vec3 { e: offset }),
+                (None, Some(angle)) => build_rotate(&object, angle),
+                _ => {
+                    return Err(self.error(
+                        span,
+                        &format!("{}.transforms[{}]", field, i),
+                        "expected exactly one of `translate` or `rotate_y`".to_string(),
+                    ))
+                }
+            };
+        }
+        Ok(object)
+    }
+    fn build_aperture(
+        &self,
+        aperture: &Spanned<ApertureDesc>,
+        field: &str,
+    ) -> Result<Aperture, SceneError> {
+        let span = aperture.span();
+        let a = aperture.get_ref();
+        match a.kind.as_str() {
+            "disk" => Ok(Aperture::Disk),
+            "polygon" => {
+                let blades = self.require(a.blades, &span, field, "blades")?;
+                if blades < 3 {
+                    return Err(self.error(
+                        span,
+                        &format!("{}.blades", field),
+                        "a polygon needs at least 3 blades".to_string(),
+                    ));
+                }
+                Ok(Aperture::Polygon {
+                    blades,
+                    rotation: a.rotation.unwrap_or(0.0),
+                })
+            }
+            "image" => {
+                let path = a.path.as_ref().ok_or_else(|| {
+                    self.error(
+                        span.clone(),
+                        &format!("{}.path", field),
+                        "missing field".to_string(),
+                    )
+                })?;
+                let mask = image::open(self.base_dir.join(path))
+                    .map_err(|e| {
+                        self.error(span.clone(), &format!("{}.path", field), e.to_string())
+                    })?
+                    .to_luma8();
+                let (width, height) = mask.dimensions();
+                let weights: Vec<f64> = mask.pixels().map(|p| p[0] as f64).collect();
+                if weights.iter().all(|w| *w == 0.0) {
+                    return Err(self.error(
+                        span,
+                        &format!("{}.path", field),
+                        "aperture image is completely black".to_string(),
+                    ));
+                }
+                Ok(Aperture::Image {
+                    distribution: Distribution2D::new(&weights, width as usize, height as usize),
+                    aspect: width as f64 / height as f64,
+                })
+            }
+            other => Err(self.error(
+                span,
+                &format!("{}.type", field),
+                format!("unknown aperture type `{}`", other),
+            )),
+        }
+    }
+    fn build_environment(
+        &self,
+        environment: &Spanned<EnvironmentDesc>,
+        field: &str,
+    ) -> Result<Environment, SceneError> {
+        let span = environment.span();
+        let e = environment.get_ref();
+        match e.kind.as_str() {
+            "map" => {
+                let path = e.path.as_ref().ok_or_else(|| {
+                    self.error(
+                        span.clone(),
+                        &format!("{}.path", field),
+                        "missing field".to_string(),
+                    )
+                })?;
+                let image = load_hdr_image(self.base_dir.join(path)).map_err(|e| {
+                    self.error(span.clone(), &format!("{}.path", field), e.to_string())
+                })?;
+                Ok(build_environment_map(
+                    image,
+                    e.rotation.unwrap_or(0.0),
+                    e.intensity.unwrap_or(1.0),
+                ))
+            }
+            "sky" => {
+                let sun = self.require_vec3(e.sun_direction, &span, field, "sun_direction")?;
+                if sun.e[1] < 0.0 || sun.sq_length() == 0.0 {
+                    return Err(self.error(
+                        span,
+                        &format!("{}.sun_direction", field),
+                        "the sun has to be above the horizon".to_string(),
+                    ));
+                }
+                let turbidity = e.turbidity.unwrap_or(3.0);
+                if !(2.0..=10.0).contains(&turbidity) {
+                    return Err(self.error(
+                        span,
+                        &format!("{}.turbidity", field),
+                        "turbidity must be between 2 and 10".to_string(),
+                    ));
+                }
+                Ok(build_sky(
+                    sun,
+                    turbidity,
+                    e.ground_albedo.unwrap_or(0.3),
+                    e.sun_size.unwrap_or(0.53),
+                    e.intensity.unwrap_or(1.0),
+                ))
+            }
+            other => Err(self.error(
+                span,
+                &format!("{}.type", field),
+                format!("unknown environment type `{}`", other),
+            )),
+        }
+    }
+    fn build_delta_light(
+        &self,
+        light: &Spanned<LightDesc>,
+        field: &str,
+    ) -> Result<DeltaLight, SceneError> {
+        let span = light.span();
+        let l = light.get_ref();
+        let intensity = self.require_vec3(l.intensity, &span, field, "intensity")?;
+        let direction = || {
+            let dir = self.require_vec3(l.direction, &span, field, "direction")?;
+            if dir.sq_length() == 0.0 {
+                return Err(self.error(
+                    span.clone(),
+                    &format!("{}.direction", field),
+                    "must not be zero".to_string(),
+                ));
+            }
+            Ok(dir)
+        };
+        match l.kind.as_str() {
+            "point" => Ok(build_point_light(
+                self.require_vec3(l.position, &span, field, "position")?,
+                intensity,
+            )),
+            "spot" => {
+                let cone_angle = self.require(l.cone_angle, &span, field, "cone_angle")?;
+                if !(cone_angle > 0.0 && cone_angle < 180.0) {
+                    return Err(self.error(
+                        span,
+                        &format!("{}.cone_angle", field),
+                        "must be between 0 and 180 degrees".to_string(),
+                    ));
+                }
+                let falloff = l.falloff.unwrap_or(0.0);
+                if falloff < 0.0 {
+                    return Err(self.error(
+                        span,
+                        &format!("{}.falloff", field),
+                        "must not be negative".to_string(),
+                    ));
+                }
+                Ok(build_spot_light(
+                    self.require_vec3(l.position, &span, field, "position")?,
+                    direction()?,
+                    intensity,
+                    cone_angle,
+                    falloff,
+                ))
+            }
+            "directional" => Ok(build_directional_light(direction()?, intensity)),
+            other => Err(self.error(
+                span,
+                &format!("{}.type", field),
+                format!("unknown light type `{}`", other),
+            )),
+        }
+    }
+    fn object_tracks(
+        &self,
+        obj: &Spanned<ObjectDesc>,
+        field: &str,
+    ) -> Result<Option<ObjectTracks>, SceneError> {
+        let o = obj.get_ref();
+        if o.keys.is_empty() {
+            return Ok(None);
+        }
+        let mut translate = Vec::new();
+        let mut rotate_y = Vec::new();
+        for (i, key) in o.keys.iter().enumerate() {
+            if key.translate.is_none() && key.rotate_y.is_none() {
+                return Err(self.error(
+                    obj.span(),
+                    &format!("{}.keys[{}]", field, i),
+                    "expected `translate` or `rotate_y`".to_string(),
+                ));
+            }
+            if let Some(x) = key.translate {
+                translate.push((key.frame, x));
+            }
+            if let Some(x) = key.rotate_y {
+                rotate_y.push((key.frame, [x]));
+            }
+        }
+        Ok(Some(ObjectTracks {
+            translate: Track::new(translate),
+            rotate_y: Track::new(rotate_y),
+        }))
+    }
+    fn build_animation(
+        &self,
+        desc: &SceneFile,
+        objects: Vec<SceneObject>,
+    ) -> Result<Animation, SceneError> {
+        let mut lookfrom = Vec::new();
+        let mut lookat = Vec::new();
+        let mut vfov = Vec::new();
+        let mut focus_dist = Vec::new();
+        let mut defocus_angle = Vec::new();
+        let mut key_frames: Vec<f64> = desc
+            .objects
+            .iter()
+            .flat_map(|o| o.get_ref().keys.iter().map(|k| k.frame))
+            .collect();
+        let mut interpolation = Interpolation::Linear;
+        let mut frames = None;
+
+        if let Some(anim) = &desc.animation {
+            let a = anim.get_ref();
+            for key in &a.camera {
+                key_frames.push(key.frame);
+                if let Some(x) = key.lookfrom {
+                    lookfrom.push((key.frame, x));
+                }
+                if let Some(x) = key.lookat {
+                    lookat.push((key.frame, x));
+                }
+                if let Some(x) = key.vfov {
+                    vfov.push((key.frame, [x]));
+                }
+                if let Some(x) = key.focus_dist {
+                    focus_dist.push((key.frame, [x]));
+                }
+                if let Some(x) = key.defocus_angle {
+                    defocus_angle.push((key.frame, [x]));
+                }
+            }
+            if let Some(x) = a.interpolation {
+                interpolation = match x {
+                    InterpolationDesc::Linear => Interpolation::Linear,
+                    InterpolationDesc::CatmullRom => Interpolation::CatmullRom,
+                };
+            }
+            if let Some([first, last]) = a.frames {
+                if first > last {
+                    return Err(self.error(
+                        anim.span(),
+                        "animation.frames",
+                        format!("first frame {} is after last frame {}", first, last),
+                    ));
+                }
+                frames = Some((first, last));
+            }
+        }
+
+        // Without an explicit range, cover every key
+        let (first_frame, last_frame) = frames.unwrap_or_else(|| {
+            let min = key_frames.iter().copied().fold(f64::INFINITY, f64::min);
+            let max = key_frames.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+            if min <= max {
+                (min.floor().max(0.0) as u32, max.ceil().max(0.0) as u32)
+            } else {
+                (1, 1)
+            }
+        });
+        Ok(Animation {
+            first_frame,
+            last_frame,
+            interpolation,
+            camera: CameraTracks {
+                lookfrom: Track::new(lookfrom),
+                lookat: Track::new(lookat),
+                vfov: Track::new(vfov),
+                focus_dist: Track::new(focus_dist),
+                defocus_angle: Track::new(defocus_angle),
+            },
+            objects,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error_of(src: &str) -> SceneError {
+        match parse(src, "test.toml", PathBuf::new()) {
+            Ok(_) => panic!("scene parsed without an error"),
+            Err(e) => e,
+        }
+    }
+
+    fn assert_error(src: &str, line: usize, field: &str, message: &str) {
+        let e = error_of(src);
+        assert_eq!((e.line, e.field.as_str()), (line, field), "{}", e);
+        assert!(e.message.contains(message), "{}", e);
+        assert!(
+            e.to_string()
+                .starts_with(&format!("test.toml:{}: {}: ", line, field)),
+            "{}",
+            e
+        );
+    }
+
+    const SPHERE: &str = r#"
+[camera]
+width = 10
+
+[materials.white]
+type = "lambertian"
+color = [1.0, 1.0, 1.0]
+
+[[objects]]
+type = "sphere"
+center = [0.0, 0.0, 0.0]
+radius = 1.0
+"#;
+
+    #[test]
+    fn wrong_type_names_its_line_and_field() {
+        let src = SPHERE.replace("width = 10", "width = \"wide\"");
+        assert_error(&src, 3, "camera.width", "invalid type");
+    }
+
+    #[test]
+    fn unknown_material_names_its_line_and_field() {
+        let src = format!("{}material = \"chrome\"\n", SPHERE);
+        assert_error(&src, 13, "objects[0].material", "unknown material `chrome`");
+    }
+
+    #[test]
+    fn unknown_key_names_its_line_and_field() {
+        let src = format!("{}material = \"white\"\nsize = 2.0\n", SPHERE);
+        assert_error(&src, 14, "objects[0].size", "unknown field `size`");
+        let src = SPHERE.replace("width = 10", "width = 10\ncolour = 3");
+        assert_error(&src, 4, "camera.colour", "unknown field `colour`");
+    }
+
+    #[test]
+    fn valid_scene_parses() {
+        let src = format!("{}material = \"white\"\n", SPHERE);
+        assert!(parse(&src, "test.toml", PathBuf::new()).is_ok());
+    }
+}
@@ -0,0 +1,214 @@
+use crate::camera::Camera;
+use crate::hittable_list::hittable::build_rotate;
+use crate::hittable_list::hittable::build_translate;
+use crate::hittable_list::HitObject;
+use crate::rtweekend::vec3::Vec3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Interpolation {
+    Linear,
+    CatmullRom, // Passes through every key with a smooth tangent
+}
+
+// Values of one parameter at some frames, kept sorted by frame. Before the first
+// and after the last key the parameter holds still.
+pub struct Track<const N: usize> {
+    keys: Vec<(f64, [f64; N])>,
+}
+impl<const N: usize> Track<N> {
+    pub fn new(mut keys: Vec<(f64, [f64; N])>) -> Track<N> {
+        keys.sort_by(|a, b| a.0.total_cmp(&b.0));
+        Track { keys }
+    }
+    pub fn at(&self, frame: f64, interpolation: Interpolation) -> Option<[f64; N]> {
+        let keys = &self.keys;
+        let last = keys.len().checked_sub(1)?;
+        if frame <= keys[0].0 {
+            return Some(keys[0].1);
+        }
+        if frame >= keys[last].0 {
+            return Some(keys[last].1);
+        }
+        // keys[k] is the last key at or before frame, so k < last here
+        let k = keys.iter().rposition(|key| key.0 <= frame)?;
+        let (f1, p1) = keys[k];
+        let (f2, p2) = keys[k + 1];
+        let span = f2 - f1;
+        let t = (frame - f1) / span;
+
+        let mut out = [0.0; N];
+        match interpolation {
+            Interpolation::Linear => {
+                for c in 0..N {
+                    out[c] = p1[c] + (p2[c] - p1[c]) * t;
+                }
+            }
+            Interpolation::CatmullRom => {
+                // Cubic Hermite with finite-difference tangents per frame, scaled to the segment
+                let m1 = self.slope(k);
+                let m2 = self.slope(k + 1);
+                let (t2, t3) = (t * t, t * t * t);
+                let h00 = 2.0 * t3 - 3.0 * t2 + 1.0;
+                let h10 = t3 - 2.0 * t2 + t;
+                let h01 = -2.0 * t3 + 3.0 * t2;
+                let h11 = t3 - t2;
+                for c in 0..N {
+                    out[c] = h00 * p1[c] + h10 * span * m1[c] + h01 * p2[c] + h11 * span * m2[c];
+                }
+            }
+        }
+        Some(out)
+    }
+    // Rate of change per frame at key k, one-sided at the ends.
+    fn slope(&self, k: usize) -> [f64; N] {
+        let keys = &self.keys;
+        let a = k.saturating_sub(1);
+        let b = (k + 1).min(keys.len() - 1);
+        let mut out = [0.0; N];
+        if b > a {
+            for (c, o) in out.iter_mut().enumerate() {
+                *o = (keys[b].1[c] - keys[a].1[c]) / (keys[b].0 - keys[a].0);
+            }
+        }
+        out
+    }
+}
+
+pub struct CameraTracks {
+    pub lookfrom: Track<3>,
+    pub lookat: Track<3>,
+    pub vfov: Track<1>,
+    pub focus_dist: Track<1>,
+    pub defocus_angle: Track<1>,
+}
+impl CameraTracks {
+    pub fn apply(&self, cam: &mut Camera, frame: f64, interpolation: Interpolation) {
+        if let Some(e) = self.lookfrom.at(frame, interpolation) {
+            cam.lookfrom = Vec3 { e };
+        }
+        if let Some(e) = self.lookat.at(frame, interpolation) {
+            cam.lookat = Vec3 { e };
+        }
+        if let Some([x]) = self.vfov.at(frame, interpolation) {
+            cam.vfov = x;
+        }
+        if let Some([x]) = self.focus_dist.at(frame, interpolation) {
+            cam.focus_dist = x;
+        }
+        if let Some([x]) = self.defocus_angle.at(frame, interpolation) {
+            cam.defocus_angle = x;
+        }
+    }
+}
+
+// Keyed transform applied on top of an object's static ones: rotate, then translate.
+pub struct ObjectTracks {
+    pub translate: Track<3>,
+    pub rotate_y: Track<1>,
+}
+impl ObjectTracks {
+    pub fn apply(&self, object: &HitObject, frame: f64, interpolation: Interpolation) -> HitObject {
+        let mut object = object.clone();
+        if let Some([angle]) = self.rotate_y.at(frame, interpolation) {
+            object = build_rotate(&object, angle);
+        }
+        if let Some(e) = self.translate.at(frame, interpolation) {
+            object = build_translate(&object, Vec3 { e });
+        }
+        object
+    }
+}
+
+// A top-level object of the scene file, kept around so animated frames can rebuild
+// the world from it.
+pub struct SceneObject {
+    pub object: HitObject,
+    pub light: bool,
+    pub tracks: Option<ObjectTracks>,
+}
+
+pub struct Animation {
+    pub first_frame: u32,
+    pub last_frame: u32,
+    pub interpolation: Interpolation,
+    pub camera: CameraTracks,
+    pub objects: Vec<SceneObject>,
+}
+impl Animation {
+    // Whether frames differ only in the camera, so the world can be reused as is.
+    pub fn static_world(&self) -> bool {
+        self.objects.iter().all(|o| o.tracks.is_none())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BOTH: [Interpolation; 2] = [Interpolation::Linear, Interpolation::CatmullRom];
+
+    // Given out of order, with uneven spacing, so sorting and per-frame tangents count.
+    fn track() -> Track<2> {
+        Track::new(vec![
+            (10.0, [4.0, -1.0]),
+            (0.0, [0.0, 0.0]),
+            (4.0, [2.0, 3.0]),
+            (16.0, [-2.0, 5.0]),
+        ])
+    }
+
+    #[test]
+    fn both_modes_pass_through_every_key() {
+        let track = track();
+        for interpolation in BOTH {
+            for (frame, value) in [
+                (0.0, [0.0, 0.0]),
+                (4.0, [2.0, 3.0]),
+                (10.0, [4.0, -1.0]),
+                (16.0, [-2.0, 5.0]),
+            ] {
+                assert_eq!(track.at(frame, interpolation), Some(value));
+            }
+        }
+    }
+
+    #[test]
+    fn linear_is_halfway_between_keys_halfway_between_their_frames() {
+        let track = track();
+        assert_eq!(track.at(2.0, Interpolation::Linear), Some([1.0, 1.5]));
+        assert_eq!(track.at(13.0, Interpolation::Linear), Some([1.0, 2.0]));
+    }
+
+    #[test]
+    fn catmull_rom_stays_continuous_across_keys() {
+        let track = track();
+        let near = |a: [f64; 2], b: [f64; 2]| (a[0] - b[0]).abs() + (a[1] - b[1]).abs() < 1e-3;
+        let key = track.at(4.0, Interpolation::CatmullRom).unwrap();
+        assert!(near(
+            track.at(3.9999, Interpolation::CatmullRom).unwrap(),
+            key
+        ));
+        assert!(near(
+            track.at(4.0001, Interpolation::CatmullRom).unwrap(),
+            key
+        ));
+    }
+
+    #[test]
+    fn frames_outside_the_keys_hold_the_end_values() {
+        let track = track();
+        for interpolation in BOTH {
+            assert_eq!(track.at(-5.0, interpolation), Some([0.0, 0.0]));
+            assert_eq!(track.at(100.0, interpolation), Some([-2.0, 5.0]));
+        }
+        let single = Track::new(vec![(3.0, [7.0])]);
+        for interpolation in BOTH {
+            assert_eq!(single.at(0.0, interpolation), Some([7.0]));
+            assert_eq!(single.at(9.0, interpolation), Some([7.0]));
+        }
+        assert_eq!(
+            Track::<1>::new(Vec::new()).at(1.0, Interpolation::Linear),
+            None
+        );
+    }
+}
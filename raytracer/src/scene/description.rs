@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+
+use serde::Deserialize;
+use toml::Spanned;
+
+// Raw layout of a scene file. Everything here mirrors the TOML keys one to one;
+// turning it into HitObject/Material/Texture happens in scene.rs.
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SceneFile {
+    pub camera: CameraDesc,
+    #[serde(default)]
+    pub textures: BTreeMap<String, Spanned<TextureDesc>>,
+    #[serde(default)]
+    pub materials: BTreeMap<String, Spanned<MaterialDesc>>,
+    #[serde(default)]
+    pub objects: Vec<Spanned<ObjectDesc>>,
+    #[serde(default)]
+    pub lights: Vec<Spanned<LightDesc>>,
+    pub animation: Option<Spanned<AnimationDesc>>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AnimationDesc {
+    pub frames: Option<[u32; 2]>,
+    pub interpolation: Option<InterpolationDesc>,
+    #[serde(default)]
+    pub camera: Vec<CameraKeyDesc>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum InterpolationDesc {
+    Linear,
+    CatmullRom,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraKeyDesc {
+    pub frame: f64,
+    pub lookfrom: Option<[f64; 3]>,
+    pub lookat: Option<[f64; 3]>,
+    pub vfov: Option<f64>,
+    pub focus_dist: Option<f64>,
+    pub defocus_angle: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CameraDesc {
+    pub aspect_ratio: Option<f64>,
+    pub width: Option<Spanned<u32>>,
+    pub samples_per_pixel: Option<Spanned<u32>>,
+    pub max_depth: Option<u32>,
+    pub rr_depth: Option<u32>,
+    pub mis: Option<MisDesc>,
+    pub background: Option<[f64; 3]>,
+    pub environment: Option<Spanned<EnvironmentDesc>>,
+
+    pub vfov: Option<f64>,
+    pub lookfrom: Option<[f64; 3]>,
+    pub lookat: Option<[f64; 3]>,
+    pub vup: Option<[f64; 3]>,
+
+    pub defocus_angle: Option<f64>,
+    pub focus_dist: Option<f64>,
+    pub aperture: Option<Spanned<ApertureDesc>>,
+
+    pub shutter: Option<[f64; 2]>,
+    pub shutter_curve: Option<ShutterCurveDesc>,
+
+    pub projection: Option<ProjectionDesc>,
+    pub viewport_height: Option<f64>,
+    pub fov: Option<f64>,
+
+    pub adaptive: Option<Spanned<AdaptiveDesc>>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ProjectionDesc {
+    Perspective,
+    Orthographic,
+    Equirectangular,
+    FisheyeEquidistant,
+    FisheyeEquisolid,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ApertureDesc {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub blades: Option<u32>,
+    pub rotation: Option<f64>,
+    pub path: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct LightDesc {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub position: Option<[f64; 3]>,
+    pub direction: Option<[f64; 3]>,
+    pub intensity: Option<[f64; 3]>,
+    pub cone_angle: Option<f64>,
+    pub falloff: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EnvironmentDesc {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub path: Option<String>,
+    pub rotation: Option<f64>,
+    pub intensity: Option<f64>,
+    pub sun_direction: Option<[f64; 3]>,
+    pub turbidity: Option<f64>,
+    pub ground_albedo: Option<f64>,
+    pub sun_size: Option<f64>,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum MisDesc {
+    Balance,
+    Power,
+}
+
+#[derive(Deserialize, Clone, Copy)]
+#[serde(rename_all = "snake_case")]
+pub enum ShutterCurveDesc {
+    Box,
+    Triangle,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdaptiveDesc {
+    pub min_spp: u32,
+    pub threshold: f64,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TextureDesc {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub color: Option<[f64; 3]>,
+    pub path: Option<String>,
+    pub scale: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MaterialDesc {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub color: Option<[f64; 3]>,
+    pub texture: Option<Spanned<String>>,
+    pub albedo: Option<[f64; 3]>,
+    pub fuzz: Option<f64>,
+    pub refraction_index: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransformDesc {
+    pub translate: Option<[f64; 3]>,
+    pub rotate_y: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TransformKeyDesc {
+    pub frame: f64,
+    pub translate: Option<[f64; 3]>,
+    pub rotate_y: Option<f64>,
+}
+
+#[derive(Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ObjectDesc {
+    #[serde(rename = "type")]
+    pub kind: String,
+    pub material: Option<Spanned<String>>,
+    #[serde(default)]
+    pub light: bool,
+    #[serde(default)]
+    pub transforms: Vec<TransformDesc>,
+    #[serde(default)]
+    pub keys: Vec<TransformKeyDesc>, // Animated transform, top-level objects only
+
+    // sphere
+    pub center: Option<[f64; 3]>,
+    pub radius: Option<f64>,
+    pub motion: Option<[f64; 3]>,
+    pub motion_time: Option<[f64; 2]>,
+    // quad
+    pub q: Option<[f64; 3]>,
+    pub u: Option<[f64; 3]>,
+    pub v: Option<[f64; 3]>,
+    // box and triangle corners
+    pub a: Option<[f64; 3]>,
+    pub b: Option<[f64; 3]>,
+    pub c: Option<[f64; 3]>,
+    // obj
+    pub path: Option<String>,
+    pub scale: Option<f64>,
+    // constant_medium
+    pub boundary: Option<Box<Spanned<ObjectDesc>>>,
+    pub density: Option<f64>,
+    pub color: Option<[f64; 3]>,
+    pub texture: Option<Spanned<String>>,
+}
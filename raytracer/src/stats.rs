@@ -0,0 +1,179 @@
+use std::cell::RefCell;
+use std::io::{self, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+// Counters for one render. Each render thread counts into its own copy through
+// count(), hands it over with every finished tile, and Camera::render adds them up.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct RenderStats {
+    pub primary_rays: u64,
+    pub secondary_rays: u64,
+    pub shadow_rays: u64, // Next-event rays towards a sampled emitter or a delta light
+    pub bvh_nodes_visited: u64,
+    pub aabb_tests: u64,
+    pub primitive_tests: PrimitiveTests,
+    pub paths: u64,
+    pub path_segments: u64, // Rays traced over all paths, for the average length
+    pub tiles: u64,
+    pub tile_seconds: f64, // Summed over threads
+    pub slowest_tile_seconds: f64,
+    pub tile_times: Vec<TileTime>, // In the order the tiles finished
+    pub wall_seconds: f64,
+    pub rays_per_second: f64, // Over the wall time
+}
+
+// How long one tile took, by its top left pixel.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct TileTime {
+    pub x: u32,
+    pub y: u32,
+    pub seconds: f64,
+}
+
+// Intersection tests per kind of HitObject leaf.
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct PrimitiveTests {
+    pub sphere: u64,
+    pub quad: u64,
+    pub triangle: u64,
+    pub constant_medium: u64,
+}
+
+thread_local! {
+    static STATS: RefCell<RenderStats> = RefCell::new(RenderStats::default());
+}
+// Off unless the stats are asked for, so the hot paths pay one load of a global
+// rather than a thread-local lookup per intersection test.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+// Updates the calling thread's counters.
+#[inline]
+pub fn count(f: impl FnOnce(&mut RenderStats)) {
+    if ENABLED.load(Ordering::Relaxed) {
+        count_enabled(f);
+    }
+}
+#[cold]
+fn count_enabled(f: impl FnOnce(&mut RenderStats)) {
+    STATS.with(|s| f(&mut s.borrow_mut()));
+}
+// Returns the calling thread's counters and starts it over from zero.
+pub fn take() -> RenderStats {
+    STATS.with(|s| std::mem::take(&mut *s.borrow_mut()))
+}
+
+impl RenderStats {
+    pub fn merge(&mut self, other: &RenderStats) {
+        self.primary_rays += other.primary_rays;
+        self.secondary_rays += other.secondary_rays;
+        self.shadow_rays += other.shadow_rays;
+        self.bvh_nodes_visited += other.bvh_nodes_visited;
+        self.aabb_tests += other.aabb_tests;
+        self.primitive_tests.sphere += other.primitive_tests.sphere;
+        self.primitive_tests.quad += other.primitive_tests.quad;
+        self.primitive_tests.triangle += other.primitive_tests.triangle;
+        self.primitive_tests.constant_medium += other.primitive_tests.constant_medium;
+        self.paths += other.paths;
+        self.path_segments += other.path_segments;
+        self.tiles += other.tiles;
+        self.tile_seconds += other.tile_seconds;
+        self.slowest_tile_seconds = self.slowest_tile_seconds.max(other.slowest_tile_seconds);
+        self.tile_times.extend_from_slice(&other.tile_times);
+    }
+    // Sets the wall time of the whole render and the rate that follows from it.
+    pub fn finish(&mut self, wall_seconds: f64) {
+        self.wall_seconds = wall_seconds;
+        self.rays_per_second = if wall_seconds > 0.0 {
+            self.rays() as f64 / wall_seconds
+        } else {
+            0.0
+        };
+    }
+    pub fn rays(&self) -> u64 {
+        self.primary_rays + self.secondary_rays + self.shadow_rays
+    }
+    pub fn report<W: Write>(&self, out: &mut W) -> io::Result<()> {
+        let per = |n: u64, d: u64| if d > 0 { n as f64 / d as f64 } else { 0.0 };
+        let rays = self.rays();
+        writeln!(out, "render time:     {:.2} s", self.wall_seconds)?;
+        writeln!(
+            out,
+            "rays:            {} ({} primary, {} secondary, {} shadow)",
+            rays, self.primary_rays, self.secondary_rays, self.shadow_rays
+        )?;
+        writeln!(out, "rays/second:     {:.3} M", self.rays_per_second / 1e6)?;
+        writeln!(
+            out,
+            "path length:     {:.2} rays on average",
+            per(self.path_segments, self.paths)
+        )?;
+        writeln!(
+            out,
+            "bvh nodes:       {} visited, {:.1} per ray",
+            self.bvh_nodes_visited,
+            per(self.bvh_nodes_visited, rays)
+        )?;
+        writeln!(
+            out,
+            "aabb tests:      {}, {:.1} per ray",
+            self.aabb_tests,
+            per(self.aabb_tests, rays)
+        )?;
+        let p = &self.primitive_tests;
+        writeln!(
+            out,
+            "primitive tests: {} sphere, {} quad, {} triangle, {} medium",
+            p.sphere, p.quad, p.triangle, p.constant_medium
+        )?;
+        writeln!(
+            out,
+            "tiles:           {}, {:.3} s average, {:.3} s slowest",
+            self.tiles,
+            if self.tiles > 0 {
+                self.tile_seconds / self.tiles as f64
+            } else {
+                0.0
+            },
+            self.slowest_tile_seconds
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_has_rays_per_second_and_every_tile() {
+        let tile = |x, y, seconds| RenderStats {
+            primary_rays: 300,
+            shadow_rays: 100,
+            tiles: 1,
+            tile_seconds: seconds,
+            slowest_tile_seconds: seconds,
+            tile_times: vec![TileTime { x, y, seconds }],
+            ..RenderStats::default()
+        };
+        let mut stats = RenderStats::default();
+        stats.merge(&tile(0, 0, 0.5));
+        stats.merge(&tile(32, 0, 1.5));
+        stats.finish(2.0);
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["rays_per_second"], 400.0);
+        assert_eq!(json["wall_seconds"], 2.0);
+        assert_eq!(json["slowest_tile_seconds"], 1.5);
+        assert_eq!(
+            json["tile_times"],
+            serde_json::json!([
+                { "x": 0, "y": 0, "seconds": 0.5 },
+                { "x": 32, "y": 0, "seconds": 1.5 },
+            ])
+        );
+    }
+}